@@ -1,129 +1,772 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use clap::{Arg, Command};
-use hyper_headset::devices::{cloud_ii_wireless_dts::CloudIIWirelessDTS, Device};
+use clap::{error::ErrorKind, Arg, ArgAction, ArgMatches, Command, ValueEnum};
+use hyper_headset::config::load_config;
+#[cfg(unix)]
+use hyper_headset::daemon;
+use hyper_headset::devices::{
+    connect_to_state, hours_and_minutes, list_compatible_devices, Capabilities,
+    Command as DeviceCommand, DeviceError, DeviceEvent, DeviceInfo, DeviceState,
+    Headset,
+};
+use hyper_headset::history::{
+    history_path, history_path_for_selector, BatteryHistory, BatteryReport,
+};
+use serde::Serialize;
 
-fn main() {
-    let matches = Command::new(env!("CARGO_PKG_NAME"))
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Human,
+    Json,
+    Toml,
+    #[value(name = "key=value")]
+    KeyValue,
+}
+
+// The values `get` can read, each is a single query
+const GETTERS: [(&str, DeviceCommand, &str); 11] = [
+    (
+        "battery",
+        DeviceCommand::GetBattery,
+        "The battery level in percent.",
+    ),
+    (
+        "charging",
+        DeviceCommand::GetCharging,
+        "The charging status.",
+    ),
+    (
+        "mute",
+        DeviceCommand::GetMute,
+        "Whether the headset is muted.",
+    ),
+    (
+        "mic-connected",
+        DeviceCommand::GetMicConnected,
+        "Whether the mic is plugged in.",
+    ),
+    (
+        "automatic-shutdown",
+        DeviceCommand::GetAutomaticShutdown,
+        "The delay in minutes after which the headset will automatically shutdown.",
+    ),
+    (
+        "pairing-info",
+        DeviceCommand::GetPairingInfo,
+        "The pairing info.",
+    ),
+    (
+        "product-color",
+        DeviceCommand::GetProductColor,
+        "The color of the headset.",
+    ),
+    (
+        "side-tone",
+        DeviceCommand::GetSideTone,
+        "Whether side tone is enabled.",
+    ),
+    (
+        "side-tone-volume",
+        DeviceCommand::GetSideToneVolume,
+        "The side tone volume.",
+    ),
+    (
+        "voice-prompt",
+        DeviceCommand::GetVoicePrompt,
+        "Whether voice prompt is enabled.",
+    ),
+    (
+        "connected",
+        DeviceCommand::GetWirelessConnected,
+        "Whether the headset is connected to the dongle.",
+    ),
+];
+
+fn setting(name: &'static str, help: &'static str, value: Arg) -> Command {
+    Command::new(name).about(help).arg(value.required(true))
+}
+
+fn cli() -> Command {
+    Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
-        .about("A CLI application for monitoring and managing HyperX headsets.")
+        .about("A CLI application for monitoring and managing HyperX headsets.\nWithout a command all available headset information is printed.")
+        .subcommand(
+            Command::new("get")
+                .about("Read a single value from the headset.")
+                .subcommand_required(true)
+                .subcommands(
+                    GETTERS
+                        .iter()
+                        .map(|(name, _, help)| Command::new(*name).about(*help)),
+                ),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Change a setting of the headset.")
+                .subcommand_required(true)
+                .subcommand(setting(
+                    "automatic-shutdown",
+                    "Set the delay in minutes after which the headset will automatically shutdown.\n0 will disable automatic shutdown.",
+                    Arg::new("minutes").value_parser(clap::value_parser!(u8)),
+                ))
+                .subcommand(setting(
+                    "mute",
+                    "Mute or un mute the headset.",
+                    Arg::new("mute").value_parser(clap::value_parser!(bool)),
+                ))
+                .subcommand(setting(
+                    "side-tone",
+                    "Enable or disable side tone.",
+                    Arg::new("enable").value_parser(clap::value_parser!(bool)),
+                ))
+                .subcommand(setting(
+                    "side-tone-volume",
+                    "Set the side tone volume.",
+                    Arg::new("volume").value_parser(clap::value_parser!(u8)),
+                ))
+                .subcommand(setting(
+                    "voice-prompt",
+                    "Enable voice prompt. This may not be supported on your device.",
+                    Arg::new("enable").value_parser(clap::value_parser!(bool)),
+                )),
+        )
+        .subcommand(Command::new("toggle-mute").about("Mute the headset if it isn't muted and unmute it otherwise, then print the new value.\nMeant for a keyboard shortcut, the tray shows the new value on screen."))
+        .subcommand(Command::new("watch").about("Print events like pressing the mute button as they arrive.\nWith --format json every event is printed as one JSON object per line.\nIf the headset is unplugged, watch waits for it to come back."))
+        .subcommand(Command::new("info").about("Print the name, IDs and serial number of the headset."))
+        .subcommand(
+            Command::new("profile")
+                .about("Select a profile from the config file and apply it to the headset, the same as --profile.\nThe tray applies it again whenever the headset connects, \"default\" selects no profile.")
+                .arg(
+                    Arg::new("name")
+                        .required(true)
+                        .help("A profile in the config file or \"default\".")
+                        .value_parser(clap::value_parser!(String)),
+                ),
+        )
+        .subcommand(
+            Command::new("battery-report")
+                .about("Print the charge cycles and how long a full charge lasted, per week.\nBased on the battery history the tray records, the headset doesn't have to be plugged in.")
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .required(false)
+                        .help("Read the battery history from this file instead of the one of the selected headset.")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        )
         .arg(
-            Arg::new("automatic_shutdown")
-                .long("automatic_shutdown")
+            Arg::new("record")
+                .long("record")
                 .required(false)
-                .help(
-                    "Set the delay in minutes after which the headset will automatically shutdown.\n0 will disable automatic shutdown.",
-                )
-                .value_parser(clap::value_parser!(u8)),
+                .global(true)
+                .help("Log every packet exchanged with the headset to a file.")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .required(false)
+                .global(true)
+                .conflicts_with("record")
+                .help("Replay a file created with --record instead of talking to a headset.")
+                .value_parser(clap::value_parser!(PathBuf)),
         )
         .arg(
-            Arg::new("mute")
-                .long("mute")
+            Arg::new("profile")
+                .long("profile")
                 .required(false)
-                .help("Mute or un mute the headset.")
-                .value_parser(clap::value_parser!(bool)),
+                .help("Select a profile from the config file and apply it to the headset.\nThe tray applies it again whenever the headset connects, \"default\" selects no profile.")
+                .value_parser(clap::value_parser!(String)),
         )
         .arg(
-            Arg::new("enable_side_tone")
-                .long("enable_side_tone")
+            Arg::new("device")
+                .long("device")
                 .required(false)
-                .help("Enable or disable side tone.")
-                .value_parser(clap::value_parser!(bool)),
+                .global(true)
+                .help("Use the headset with this serial number, path or vendor:product ID, see --list_devices.\nDefaults to the device in the config file, otherwise the first headset found is used.")
+                .value_parser(clap::value_parser!(String)),
         )
         .arg(
-            Arg::new("side_tone_volume")
-                .long("side_tone_volume")
+            Arg::new("format")
+                .long("format")
                 .required(false)
-                .help("Set the side tone volume.")
-                .value_parser(clap::value_parser!(u8)),
+                .global(true)
+                .default_value("human")
+                .help("How to print the headset information.\nUnknown values are null in json, left out in toml and empty in key=value.")
+                .value_parser(clap::value_parser!(Format)),
         )
         .arg(
-            Arg::new("enable_voice_prompt")
-                .long("enable_voice_prompt")
+            Arg::new("list_devices")
+                .long("list_devices")
                 .required(false)
-                .help("Enable voice prompt. This may not be supported on your device.")
-                .value_parser(clap::value_parser!(bool)),
+                .help("List all connected compatible headsets.")
+                .action(ArgAction::SetTrue),
         )
-        .get_matches();
+}
 
-    let mut device = match CloudIIWirelessDTS::new() {
-        Ok(device) => device,
-        Err(error) => {
-            eprintln!("{error}");
-            std::process::exit(1);
+/// Hides the values the headset can't read and the settings it can't change.
+fn hide_unsupported(command: Command, device: &dyn Headset) -> Command {
+    let capabilities: Capabilities = device.capabilities();
+    let unsupported_setters = [
+        ("automatic-shutdown", capabilities.automatic_shutdown),
+        ("mute", capabilities.mute),
+        ("side-tone", capabilities.side_tone),
+        ("side-tone-volume", capabilities.side_tone_volume),
+        ("voice-prompt", capabilities.voice_prompt),
+    ]
+    .into_iter()
+    .filter(|(_, access)| !access.writable);
+    let unsupported_getters = GETTERS
+        .iter()
+        .filter(|(_, getter, _)| !device.supports(*getter));
+    command
+        .mut_subcommand("set", |set| {
+            unsupported_setters.fold(set, |set, (name, _)| {
+                set.mut_subcommand(name, |s| s.hide(true))
+            })
+        })
+        .mut_subcommand("get", |get| {
+            unsupported_getters.fold(get, |get, (name, _, _)| {
+                get.mut_subcommand(name, |g| g.hide(true))
+            })
+        })
+}
+
+/// Prints the help or the usage error and exits.
+/// The help for a connected headset only shows what it supports.
+fn exit_with_usage(error: clap::Error) -> ! {
+    if error.kind() == ErrorKind::DisplayHelp {
+        let preliminary = cli()
+            .ignore_errors(true)
+            .disable_help_flag(true)
+            .disable_version_flag(true)
+            .try_get_matches();
+        // Nothing is recorded for the help.
+        let matches = preliminary.ok().filter(|m| m.get_one::<PathBuf>("record").is_none());
+        if let Some(matches) = matches {
+            if let Ok((device, _)) = open_device(&matches) {
+                hide_unsupported(cli(), device.as_ref()).get_matches();
+            }
         }
+    }
+    error.exit()
+}
+
+fn main() {
+    let matches = cli()
+        .try_get_matches()
+        .unwrap_or_else(|error| exit_with_usage(error));
+
+    if matches.get_flag("list_devices") {
+        match list_compatible_devices() {
+            Ok(devices) => devices.iter().for_each(|device| println!("{device}")),
+            Err(error) => exit_with_error(error),
+        }
+        return;
+    }
+
+    let profile = matches.get_one::<String>("profile");
+    if profile.is_some() && matches.subcommand().is_some() {
+        cli()
+            .bin_name(env!("CARGO_BIN_NAME"))
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--profile can't be combined with a command",
+            )
+            .exit();
+    }
+
+    let format = *matches
+        .get_one::<Format>("format")
+        .unwrap_or(&Format::Human);
+    if let Some(("battery-report", report)) = matches.subcommand() {
+        battery_report(&matches, report, format);
+        return;
+    }
+
+    let (mut device, info) = match open_device(&matches) {
+        Ok(device) => device,
+        Err(error) => exit_with_error(error),
     };
+    if let Some(profile) = profile {
+        apply_profile(device.as_mut(), profile);
+        return;
+    }
 
-    if let Some(delay) = matches.get_one::<u8>("automatic_shutdown") {
-        let delay = *delay as u64;
-        if let Some(packet) =
-            device.set_automatic_shut_down_packet(Duration::from_secs(delay * 60u64))
-        {
-            if let Err(err) = device.get_device_state().hid_device.write(&packet) {
-                println!("Failed to set automatic shutdown with error: {:?}", err)
+    match matches.subcommand() {
+        Some(("get", get)) => {
+            let (name, _) = get.subcommand().expect("a value is required");
+            let (_, getter, _) = GETTERS
+                .iter()
+                .find(|(getter, _, _)| *getter == name)
+                .expect("every get subcommand has a getter");
+            match device.query(*getter) {
+                Ok(event) => print_formatted(&event_values(&event), &event, format),
+                Err(error) => exit_with_error(error),
             }
-        } else {
-            println!("Automatic shutdown can't be enabled on this device")
+        }
+        Some(("set", set)) => set_setting(device.as_mut(), set, format),
+        Some(("toggle-mute", _)) => match device.toggle_mute() {
+            Ok(muted) => {
+                let event = DeviceEvent::Muted(muted);
+                print_formatted(&event_values(&event), &event, format);
+            }
+            Err(DeviceError::NotSupported(_)) => {
+                eprintln!("Can't mute this device");
+                std::process::exit(1);
+            }
+            Err(error) => exit_with_error(error),
+        },
+        Some(("watch", _)) => watch(device, &matches, format),
+        Some(("profile", profile)) => {
+            apply_profile(device.as_mut(), profile.get_one::<String>("name").unwrap())
+        }
+        Some(("info", _)) => {
+            let state = device.get_device_state();
+            let info = info.unwrap_or_else(|| DeviceInfo {
+                path: String::new(),
+                serial_number: None,
+                vendor_id: state.vendor_id,
+                product_id: state.product_id,
+                product_string: state.device_name.clone(),
+            });
+            print_formatted(&info, &HumanInfo(&info), format);
+        }
+        _ => {
+            if let Err(error) = device.refresh_state() {
+                exit_with_error(error);
+            };
+            add_estimates(device.get_device_state_mut(), info.as_ref());
+            print_formatted(device.get_device_state(), device.get_device_state(), format);
         }
     }
+}
 
-    if let Some(mute) = matches.get_one::<bool>("mute") {
-        if let Some(packet) = device.set_mute_packet(*mute) {
-            if let Err(err) = device.get_device_state().hid_device.write(&packet) {
-                println!("Failed to mute with error: {:?}", err)
+/// Prints the value read back, a setting the headset didn't take exits with an error.
+fn set_setting(device: &mut dyn Headset, set: &ArgMatches, format: Format) {
+    let (command, not_supported) = match set.subcommand() {
+        Some(("automatic-shutdown", value)) => {
+            let delay =
+                Duration::from_secs(*value.get_one::<u8>("minutes").unwrap() as u64 * 60u64);
+            (
+                DeviceCommand::SetAutomaticShutdown(delay),
+                "Automatic shutdown can't be enabled on this device",
+            )
+        }
+        Some(("mute", value)) => (
+            DeviceCommand::SetMute(*value.get_one::<bool>("mute").unwrap()),
+            "Can't mute this device",
+        ),
+        Some(("side-tone", value)) => (
+            DeviceCommand::SetSideTone(*value.get_one::<bool>("enable").unwrap()),
+            "Can't enable side tone on this device",
+        ),
+        Some(("side-tone-volume", value)) => {
+            let volume = *value.get_one::<u8>("volume").unwrap();
+            let range = device.capabilities().side_tone_volume_range;
+            if !range.contains(&volume) {
+                eprintln!(
+                    "The side tone volume has to be between {} and {}",
+                    range.start(),
+                    range.end()
+                );
+                std::process::exit(1);
             }
-        } else {
-            println!("Can't mute this device")
+            (
+                DeviceCommand::SetSideToneVolume(volume),
+                "Can't set side tone volume on this device",
+            )
         }
+        Some(("voice-prompt", value)) => (
+            DeviceCommand::SetVoicePrompt(*value.get_one::<bool>("enable").unwrap()),
+            "Can't enable voice prompt on this device",
+        ),
+        _ => unreachable!("a setting is required"),
+    };
+    match device.apply(command) {
+        Ok(Some(event)) => print_formatted(&event_values(&event), &event, format),
+        Ok(None) => {
+            eprintln!("The headset can't read the setting back, it may not have been applied")
+        }
+        Err(DeviceError::NotSupported(_)) => {
+            eprintln!("{not_supported}");
+            std::process::exit(1);
+        }
+        Err(error) => exit_with_error(error),
     }
+}
 
-    if let Some(enable) = matches.get_one::<bool>("enable_side_tone") {
-        if let Some(packet) = device.set_side_tone_packet(*enable) {
-            if let Err(err) = device.get_device_state().hid_device.write(&packet) {
-                println!("Failed to enable side tone with error: {:?}", err)
+fn watch(mut device: Box<dyn Headset>, matches: &ArgMatches, format: Format) {
+    if !matches!(format, Format::Human | Format::Json) {
+        eprintln!("watch only supports --format human and json");
+        std::process::exit(1);
+    }
+    loop {
+        match device.next_event(Duration::from_secs(1)) {
+            Ok(Some(event)) => {
+                let timestamp = rfc3339(SystemTime::now());
+                match format {
+                    Format::Json => {
+                        let mut line = serde_json::Map::new();
+                        line.insert("timestamp".to_string(), timestamp.into());
+                        if let Ok(serde_json::Value::Object(event)) = serde_json::to_value(event) {
+                            line.extend(event);
+                        }
+                        println!("{}", serde_json::Value::Object(line));
+                    }
+                    _ => println!("{timestamp} {event}"),
+                }
+            }
+            Ok(None) => (),
+            Err(error) => {
+                eprintln!("{} Lost the headset: {error}", rfc3339(SystemTime::now()));
+                device = reconnect(matches);
+                eprintln!("{} Reconnected", rfc3339(SystemTime::now()));
             }
-        } else {
-            println!("Can't enable side tone on this device")
         }
     }
+}
 
-    if let Some(volume) = matches.get_one::<u8>("side_tone_volume") {
-        if let Some(packet) = device.set_side_tone_volume_packet(*volume) {
-            if let Err(err) = device.get_device_state().hid_device.write(&packet) {
-                println!("Failed to set side tone volume with error: {:?}", err)
-            }
-        } else {
-            println!("Can't set side tone volume on this device")
+/// Retries opening the headset every second until it is plugged in again.
+fn reconnect(matches: &ArgMatches) -> Box<dyn Headset> {
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        if let Ok((device, _)) = open_device(matches) {
+            return device;
+        }
+    }
+}
+
+/// Formats `time` in UTC as RFC 3339 with milliseconds, e.g. `2023-11-14T22:13:20.000Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, seconds) = (since_epoch.as_secs() / 86400, since_epoch.as_secs() % 86400);
+    // Converts days since the epoch to a date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// `{"battery_level": 73}` for `DeviceEvent::BatterLevel(73)`, matching the keys of the serialized `DeviceState`.
+fn event_values(event: &DeviceEvent) -> BTreeMap<String, serde_json::Value> {
+    match serde_json::to_value(event) {
+        Ok(serde_json::Value::Object(mut event)) => {
+            let name = event
+                .remove("event")
+                .and_then(|name| name.as_str().map(str::to_string));
+            let value = event.remove("value").unwrap_or_default();
+            name.map(|name| BTreeMap::from([(name, value)]))
+                .unwrap_or_default()
         }
+        _ => BTreeMap::new(),
+    }
+}
+
+struct HumanInfo<'a>(&'a DeviceInfo);
+
+impl Display for HumanInfo<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unknown = "Unknown".to_string();
+        write!(
+            f,
+            "Name:       {}
+Vendor ID:  {:04x}
+Product ID: {:04x}
+Serial:     {}
+Path:       {}",
+            self.0.product_string.as_ref().unwrap_or(&unknown),
+            self.0.vendor_id,
+            self.0.product_id,
+            self.0.serial_number.as_ref().unwrap_or(&unknown),
+            Some(&self.0.path)
+                .filter(|path| !path.is_empty())
+                .unwrap_or(&unknown),
+        )
     }
+}
 
-    if let Some(enable) = matches.get_one::<bool>("enable_voice_prompt") {
-        if let Some(packet) = device.set_voice_prompt_packet(*enable) {
-            if let Err(err) = device.get_device_state().hid_device.write(&packet) {
-                println!("Failed to enable voice prompt with error: {:?}", err)
+struct HumanReport<'a>(&'a BatteryReport);
+
+impl Display for HumanReport<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let runtime = |minutes: Option<u64>| {
+            minutes.map_or("Unknown".to_string(), |minutes| {
+                hours_and_minutes(Duration::from_secs(minutes * 60))
+            })
+        };
+        let report = self.0;
+        writeln!(f, "Charge cycles:       {:.1}", report.charge_cycles)?;
+        writeln!(f, "Charges:             {}", report.charges)?;
+        writeln!(
+            f,
+            "Full charge runtime: {}",
+            runtime(report.full_charge_runtime_minutes)
+        )?;
+        write!(
+            f,
+            "Runtime trend:       {}",
+            report
+                .runtime_trend_percent_per_month
+                .map_or("Unknown".to_string(), |trend| format!("{trend:+.1}% per month"))
+        )?;
+        for week in &report.weeks {
+            let start = rfc3339(UNIX_EPOCH + Duration::from_secs(week.start));
+            write!(
+                f,
+                "\nWeek of {}:  {} per full charge",
+                &start[..10],
+                runtime(week.full_charge_runtime_minutes)
+            )?;
+            for cycle in &week.cycles {
+                let start = rfc3339(UNIX_EPOCH + Duration::from_secs(cycle.start));
+                write!(
+                    f,
+                    "\n  {} {}  used {}% in {}, {} per full charge",
+                    &start[..10],
+                    &start[11..16],
+                    cycle.used_percent,
+                    hours_and_minutes(Duration::from_secs(cycle.runtime_minutes * 60)),
+                    runtime(cycle.full_charge_runtime_minutes)
+                )?;
             }
-        } else {
-            println!("Can't enable voice prompt on this device")
+        }
+        Ok(())
+    }
+}
+
+fn print_formatted(value: &impl Serialize, human: &impl Display, format: Format) {
+    match format_output(value, human, format) {
+        Ok(output) => println!("{output}"),
+        Err(error) => {
+            eprintln!("{error}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn format_output(
+    value: &impl Serialize,
+    human: &impl Display,
+    format: Format,
+) -> Result<String, String> {
+    match format {
+        Format::Human => Ok(human.to_string()),
+        Format::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+        Format::Toml => toml::to_string(value)
+            .map(|value| value.trim_end().to_string())
+            .map_err(|e| e.to_string()),
+        Format::KeyValue => {
+            let serde_json::Value::Object(values) =
+                serde_json::to_value(value).map_err(|e| e.to_string())?
+            else {
+                return Err("The value is not a map".to_string());
+            };
+            let lines: Vec<String> = values
+                .into_iter()
+                .map(|(key, value)| match value {
+                    serde_json::Value::Null => format!("{key}="),
+                    serde_json::Value::String(value) => format!("{key}={value}"),
+                    value => format!("{key}={value}"),
+                })
+                .collect();
+            Ok(lines.join("\n"))
         }
     }
+}
 
-    std::thread::sleep(Duration::from_secs_f64(0.5));
+fn exit_with_error(error: DeviceError) -> ! {
+    eprintln!("{error}");
+    std::process::exit(1);
+}
 
-    if let Err(error) = device.refresh_state() {
-        eprintln!("{error}");
+/// Estimates the battery runtime from the history the tray records, unless the tray already did.
+fn add_estimates(state: &mut DeviceState, info: Option<&DeviceInfo>) {
+    if state.time_remaining.is_some() || state.time_to_full.is_some() {
+        return;
+    }
+    if let Some(Ok(history)) = info.map(BatteryHistory::for_device) {
+        history.update_estimates(state, SystemTime::now());
+    }
+}
+
+/// Selects `profile` and sends its settings, settings the headset doesn't support are skipped.
+fn apply_profile(device: &mut dyn Headset, profile: &str) {
+    let mut config = load_config();
+    if let Err(error) = config.select_profile(profile) {
+        exit_with_error(error);
+    }
+    for command in config.active_settings().commands() {
+        match device.apply(command) {
+            Ok(_) | Err(DeviceError::NotSupported(_)) => (),
+            Err(error) => exit_with_error(error),
+        }
+    }
+}
+
+fn battery_report(matches: &ArgMatches, report: &ArgMatches, format: Format) {
+    if matches.get_one::<PathBuf>("replay").is_some() {
+        eprintln!("A replayed headset has no battery history");
         std::process::exit(1);
+    }
+    let path = match history_file(matches, report) {
+        Ok(path) => path,
+        Err(DeviceError::NoDeviceFound()) => {
+            eprintln!("No headset found, select one with --device or pick a history with --file");
+            std::process::exit(1);
+        }
+        Err(error) => exit_with_error(error),
     };
-    println!(
-        "{}",
-        device.get_device_state()
-    );
+    match BatteryHistory::read(&path) {
+        Ok(history) => {
+            let report = history.report();
+            print_formatted(&report, &HumanReport(&report), format);
+        }
+        Err(DeviceError::IoError(error)) if error.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!(
+                "There is no battery history in {}, the tray records it while it runs",
+                path.display()
+            );
+            std::process::exit(1);
+        }
+        Err(error) => exit_with_error(error),
+    }
+}
+
+/// The battery history of the selected headset, found without opening it to report on a
+/// report for a headset that isn't plugged in. Without a headset it is picked by the selector.
+fn history_file(matches: &ArgMatches, report: &ArgMatches) -> Result<PathBuf, DeviceError> {
+    if let Some(path) = report.get_one::<PathBuf>("file") {
+        return Ok(path.clone());
+    }
+    let selector = selector(matches);
+    let matching = |info: &DeviceInfo| selector.as_ref().is_none_or(|s| info.matches(s));
+    #[cfg(unix)]
+    let served = daemon::client::connect(selector.as_deref())
+        .ok()
+        .flatten()
+        .map(|device| device.info().clone());
+    #[cfg(not(unix))]
+    let served = None;
+    let info = served.or_else(|| {
+        list_compatible_devices()
+            .ok()?
+            .into_iter()
+            .find(|info| matching(info))
+    });
+    match (info, selector) {
+        (Some(info), _) => history_path(&info),
+        (None, Some(selector)) => history_path_for_selector(&selector),
+        (None, None) => return Err(DeviceError::NoDeviceFound()),
+    }
+    .ok_or_else(|| DeviceError::IoError(std::io::Error::other("no home directory")))
+}
+
+/// The headset selected with --device or in the config file.
+fn selector(matches: &ArgMatches) -> Option<String> {
+    matches
+        .get_one::<String>("device")
+        .cloned()
+        .or_else(|| load_config().device)
+}
+
+/// Opens the selected headset, the `DeviceInfo` is missing when replaying a recording.
+/// A headset held open by the tray is used through the tray, unless its packets are recorded.
+fn open_device(matches: &ArgMatches) -> Result<(Box<dyn Headset>, Option<DeviceInfo>), DeviceError> {
+    let (state, info) = if let Some(path) = matches.get_one::<PathBuf>("replay") {
+        (DeviceState::from_recording(path)?, None)
+    } else {
+        let selector = selector(matches);
+        #[cfg(unix)]
+        if matches.get_one::<PathBuf>("record").is_none() {
+            if let Some(device) = daemon::client::connect(selector.as_deref())? {
+                let info = device.info().clone();
+                return Ok((Box::new(device), Some(info)));
+            }
+        }
+        let info = list_compatible_devices()?
+            .into_iter()
+            .find(|info| selector.as_ref().is_none_or(|selector| info.matches(selector)))
+            .ok_or(DeviceError::NoDeviceFound())?;
+        (DeviceState::open(&info)?, Some(info))
+    };
+    let state = match matches.get_one::<PathBuf>("record") {
+        Some(path) => state.record_to(path)?,
+        None => state,
+    };
+    Ok((Box::new(connect_to_state(state)?), info))
 }
 
 #[test]
 fn test_basic_device_access() {
-    let _ = match CloudIIWirelessDTS::new() {
+    let _ = match hyper_headset::devices::connect_compatible_device() {
         Ok(device) => device,
         Err(_) => return,
     };
 }
+
+#[test]
+fn test_rfc3339() {
+    assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+    assert_eq!(rfc3339(time), "2023-11-14T22:13:20.123Z");
+    let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+    assert_eq!(rfc3339(leap_day), "2000-02-29T00:00:00.000Z");
+}
+
+#[test]
+fn test_battery_report_without_a_headset() {
+    let path = std::env::temp_dir().join(format!("hyper_headset_{}.jsonl", std::process::id()));
+    let samples = [
+        r#"{"time":1700000000,"level":100,"charging":"not_charging"}"#,
+        r#"{"time":1700036000,"level":40,"charging":"not_charging"}"#,
+    ];
+    std::fs::write(&path, samples.join("\n")).unwrap();
+    let matches = cli()
+        .try_get_matches_from([
+            "hyper_headset_cli".as_ref(),
+            "battery-report".as_ref(),
+            "--file".as_ref(),
+            path.as_os_str(),
+        ])
+        .unwrap();
+    let (_, report) = matches.subcommand().unwrap();
+    assert_eq!(history_file(&matches, report).unwrap(), path);
+    let history = BatteryHistory::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(history.samples().len(), 2);
+    assert!(!history.report().weeks.is_empty());
+
+    // No headset with these IDs is plugged in or served by a tray.
+    let matches = cli()
+        .try_get_matches_from(["hyper_headset_cli", "--device", "03f0:ffff", "battery-report"])
+        .unwrap();
+    let (_, report) = matches.subcommand().unwrap();
+    assert_eq!(
+        history_file(&matches, report).ok(),
+        history_path_for_selector("03f0:ffff")
+    );
+}
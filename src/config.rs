@@ -0,0 +1,326 @@
+use crate::devices::{Command, DeviceError};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const DEFAULT_REFRESH_INTERVAL: u64 = 60;
+const DEFAULT_NOTIFY_BATTERY_LEVELS: [u8; 3] = [20, 10, 5];
+/// Selects no profile, only `[settings]` are applied.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// The settings in `config.toml`, everything is optional and command line arguments take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Seconds between battery level queries.
+    pub refresh_interval: Option<u64>,
+    /// Battery levels in percent that show a notification.
+    pub notify_battery_levels: Option<Vec<u8>>,
+    pub notifications: Option<bool>,
+    /// Mirrors the mute button onto the microphone of the sound server and back.
+    pub sync_mute: Option<bool>,
+    /// Serial number, path or vendor:product ID of the headset to use, see `DeviceInfo::matches`.
+    pub device: Option<String>,
+    /// Applied whenever a headset connects.
+    #[serde(default)]
+    pub settings: Settings,
+    /// Named settings applied on top of `settings` while selected.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Settings>,
+    /// The selected profile, stored in `profile_path` instead of the config file.
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
+
+/// Headset settings, left out ones are not changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub mute: Option<bool>,
+    pub side_tone: Option<bool>,
+    pub side_tone_volume: Option<u8>,
+    /// Minutes, 0 disables the automatic shutdown.
+    pub automatic_shutdown: Option<u8>,
+    pub voice_prompt: Option<bool>,
+}
+
+impl Settings {
+    /// These settings with the ones in `other` taking precedence.
+    pub fn overlay(&self, other: &Settings) -> Settings {
+        Settings {
+            mute: other.mute.or(self.mute),
+            side_tone: other.side_tone.or(self.side_tone),
+            side_tone_volume: other.side_tone_volume.or(self.side_tone_volume),
+            automatic_shutdown: other.automatic_shutdown.or(self.automatic_shutdown),
+            voice_prompt: other.voice_prompt.or(self.voice_prompt),
+        }
+    }
+
+    /// The commands applying these settings.
+    pub fn commands(&self) -> Vec<Command> {
+        [
+            self.mute.map(Command::SetMute),
+            self.side_tone.map(Command::SetSideTone),
+            self.side_tone_volume.map(Command::SetSideToneVolume),
+            self.automatic_shutdown.map(|minutes| {
+                Command::SetAutomaticShutdown(Duration::from_secs(minutes as u64 * 60))
+            }),
+            self.voice_prompt.map(Command::SetVoicePrompt),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl Config {
+    pub fn from_toml(config: &str) -> Result<Self, DeviceError> {
+        toml::from_str(config).map_err(|e| DeviceError::InvalidConfig(e.message().to_string()))
+    }
+
+    /// A missing file is the default config.
+    pub fn load(path: &Path) -> Result<Self, DeviceError> {
+        match std::fs::read_to_string(path) {
+            Ok(config) => Config::from_toml(&config).map_err(|e| match e {
+                DeviceError::InvalidConfig(e) => {
+                    DeviceError::InvalidConfig(format!("{}: {e}", path.display()))
+                }
+                e => e,
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_interval.unwrap_or(DEFAULT_REFRESH_INTERVAL))
+    }
+
+    pub fn notify_battery_levels(&self) -> &[u8] {
+        self.notify_battery_levels
+            .as_deref()
+            .unwrap_or(&DEFAULT_NOTIFY_BATTERY_LEVELS)
+    }
+
+    pub fn notifications(&self) -> bool {
+        self.notifications.unwrap_or(true)
+    }
+
+    pub fn sync_mute(&self) -> bool {
+        self.sync_mute.unwrap_or(false)
+    }
+
+    /// `settings` with the selected profile applied, an unknown profile is ignored.
+    pub fn active_settings(&self) -> Settings {
+        match self
+            .profile
+            .as_ref()
+            .and_then(|name| self.profiles.get(name))
+        {
+            Some(profile) => self.settings.overlay(profile),
+            None => self.settings.clone(),
+        }
+    }
+
+    /// Remembers `name` as the selected profile, `DEFAULT_PROFILE` selects none.
+    pub fn select_profile(&mut self, name: &str) -> Result<(), DeviceError> {
+        let path = profile_path().ok_or(DeviceError::InvalidConfig(
+            "no home directory to store the profile in".to_string(),
+        ))?;
+        if name == DEFAULT_PROFILE {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            self.profile = None;
+            return Ok(());
+        }
+        if !self.profiles.contains_key(name) {
+            return Err(DeviceError::InvalidConfig(format!(
+                "unknown profile {name}"
+            )));
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, name)?;
+        self.profile = Some(name.to_string());
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/hyper_headset`, falling back to `~/.config/hyper_headset`.
+pub fn config_dir() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("hyper_headset"))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+/// `$XDG_STATE_HOME/hyper_headset`, falling back to `~/.local/state/hyper_headset`.
+pub fn state_dir() -> Option<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    Some(state_dir.join("hyper_headset"))
+}
+
+/// Holds the name of the selected profile.
+pub fn profile_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("profile"))
+}
+
+/// Loads `config_path` and the selected profile, an invalid file is reported and the default config is used instead.
+pub fn load_config() -> Config {
+    let mut config = match config_path() {
+        Some(path) => Config::load(&path).unwrap_or_else(|e| {
+            eprintln!("Ignoring config file: {e}");
+            Config::default()
+        }),
+        None => Config::default(),
+    };
+    config.profile = profile_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    config
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::from_toml(
+        r#"
+        refresh_interval = 30
+        notify_battery_levels = [15, 5]
+        device = "ABC123"
+
+        [settings]
+        side_tone = true
+        automatic_shutdown = 20
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.refresh_interval(), Duration::from_secs(30));
+    assert_eq!(config.notify_battery_levels(), [15, 5]);
+    assert_eq!(config.device.as_deref(), Some("ABC123"));
+    assert_eq!(
+        config.settings.commands(),
+        [
+            Command::SetSideTone(true),
+            Command::SetAutomaticShutdown(Duration::from_secs(20 * 60))
+        ]
+    );
+
+    let default = Config::from_toml("").unwrap();
+    assert_eq!(default.refresh_interval(), Duration::from_secs(60));
+    assert_eq!(default.notify_battery_levels(), [20, 10, 5]);
+    assert!(default.settings.commands().is_empty());
+    assert!(default.notifications());
+    assert!(!default.sync_mute());
+    assert!(!Config::from_toml("notifications = false").unwrap().notifications());
+    assert!(Config::from_toml("sync_mute = true").unwrap().sync_mute());
+    assert!(Config::from_toml("refresh = 30").is_err());
+    assert!(Config::from_toml("[settings]\nside_tone_volume = 300").is_err());
+    assert!(Config::from_toml("[settings]\nvolume = 3").is_err());
+    assert!(Config::from_toml("refresh_interval = \"30\"").is_err());
+}
+
+#[test]
+fn test_profiles() {
+    let mut config = Config::from_toml(
+        r#"
+        [settings]
+        side_tone = true
+        automatic_shutdown = 20
+
+        [profiles.meeting]
+        side_tone = false
+        mute = false
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.active_settings(), config.settings);
+    config.profile = Some("meeting".to_string());
+    let settings = config.active_settings();
+    assert_eq!(settings.side_tone, Some(false));
+    assert_eq!(settings.mute, Some(false));
+    assert_eq!(settings.automatic_shutdown, Some(20));
+    config.profile = Some("unknown".to_string());
+    assert_eq!(config.active_settings(), config.settings);
+}
+
+#[test]
+fn test_active_settings_commands() {
+    let mut config = Config::from_toml(
+        r#"
+        [settings]
+        mute = true
+        side_tone = true
+        side_tone_volume = 8
+        automatic_shutdown = 0
+        voice_prompt = false
+
+        [profiles.quiet]
+        side_tone = false
+        automatic_shutdown = 30
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.active_settings().commands(),
+        [
+            Command::SetMute(true),
+            Command::SetSideTone(true),
+            Command::SetSideToneVolume(8),
+            Command::SetAutomaticShutdown(Duration::ZERO),
+            Command::SetVoicePrompt(false),
+        ]
+    );
+    config.profile = Some("quiet".to_string());
+    assert_eq!(
+        config.active_settings().commands(),
+        [
+            Command::SetMute(true),
+            Command::SetSideTone(false),
+            Command::SetSideToneVolume(8),
+            Command::SetAutomaticShutdown(Duration::from_secs(30 * 60)),
+            Command::SetVoicePrompt(false),
+        ]
+    );
+}
+
+#[test]
+fn test_reload_changes_active_settings() {
+    // The tray applies the settings again when a reloaded config changes them.
+    let current = Config::from_toml("[settings]\nside_tone = true").unwrap();
+    let mut reloaded =
+        Config::from_toml("refresh_interval = 5\n[settings]\nside_tone = true").unwrap();
+    assert_ne!(reloaded, current);
+    assert_eq!(reloaded.active_settings(), current.active_settings());
+
+    reloaded.profiles.insert(
+        "meeting".to_string(),
+        Settings {
+            mute: Some(false),
+            ..Settings::default()
+        },
+    );
+    assert_eq!(reloaded.active_settings(), current.active_settings());
+    reloaded.profile = Some("meeting".to_string());
+    assert_ne!(reloaded.active_settings(), current.active_settings());
+    assert_eq!(
+        reloaded.active_settings().commands(),
+        [Command::SetMute(false), Command::SetSideTone(true)]
+    );
+}
@@ -0,0 +1,332 @@
+use crate::daemon::{socket_path, ApiRequest, ApiResponse, ServedDevice};
+use crate::devices::{
+    minutes, Capabilities, ChargingStatus, Color, Command, DeviceError, DeviceEvent, DeviceInfo,
+    DeviceState, Headset,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// The tray waits up to 5 seconds for the headset before it answers.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to the headset matching `selector` through the tray listening on `socket_path`.
+/// `Ok(None)` if no tray serves a matching headset, it can then be opened directly.
+pub fn connect(selector: Option<&str>) -> Result<Option<RemoteDevice>, DeviceError> {
+    match socket_path() {
+        Some(path) => connect_to(&path, selector),
+        None => Ok(None),
+    }
+}
+
+pub fn connect_to(
+    path: &Path,
+    selector: Option<&str>,
+) -> Result<Option<RemoteDevice>, DeviceError> {
+    let Ok(mut connection) = Connection::open(path) else {
+        return Ok(None);
+    };
+    let devices: Vec<ServedDevice> = connection.request(&ApiRequest::Devices)?;
+    let Some(served) = devices
+        .into_iter()
+        .find(|served| selector.is_none_or(|selector| served.info.matches(selector)))
+    else {
+        return Ok(None);
+    };
+    let mut state = DeviceState::without_transport(served.info.product_id, served.info.vendor_id);
+    state.device_name = served.info.product_string.clone();
+    Ok(Some(RemoteDevice {
+        served,
+        state,
+        path: path.to_path_buf(),
+        connection,
+        events: None,
+    }))
+}
+
+/// A headset held open by the tray, commands and events go through its socket.
+pub struct RemoteDevice {
+    served: ServedDevice,
+    state: DeviceState,
+    path: PathBuf,
+    connection: Connection,
+    // A subscription takes over its connection, so it gets its own.
+    events: Option<Connection>,
+}
+
+impl RemoteDevice {
+    pub fn info(&self) -> &DeviceInfo {
+        &self.served.info
+    }
+
+    fn selector(&self) -> Option<String> {
+        Some(self.served.info.path.clone())
+    }
+}
+
+impl Headset for RemoteDevice {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::new(
+            |command| self.served.supports(command),
+            self.served.side_tone_volume_range.clone(),
+        )
+    }
+
+    fn supports(&self, command: Command) -> bool {
+        self.served.supports(command)
+    }
+
+    fn get_device_state(&self) -> &DeviceState {
+        &self.state
+    }
+
+    fn get_device_state_mut(&mut self) -> &mut DeviceState {
+        &mut self.state
+    }
+
+    /// Subscribes to the changes of the state on the first call.
+    fn next_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DeviceError> {
+        let events = match &mut self.events {
+            Some(events) => events,
+            None => {
+                let mut events = Connection::open(&self.path)?;
+                events.request::<()>(&ApiRequest::Subscribe {
+                    device: self.selector(),
+                })?;
+                self.events.insert(events)
+            }
+        };
+        // A zero timeout would block forever
+        let timeout = timeout.max(Duration::from_millis(1));
+        match events.read(timeout)? {
+            Some(response) => response.into_result().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Flips the mute in the tray, which also shows the new value on screen.
+    fn toggle_mute(&mut self) -> Result<bool, DeviceError> {
+        let request = ApiRequest::ToggleMute {
+            device: self.selector(),
+        };
+        match self.connection.request::<Option<DeviceEvent>>(&request)? {
+            Some(DeviceEvent::Muted(muted)) => Ok(muted),
+            _ => Err(DeviceError::NoResponse()),
+        }
+    }
+
+    /// The tray already read the setting back.
+    fn apply(&mut self, setting: Command) -> Result<Option<DeviceEvent>, DeviceError> {
+        let request = ApiRequest::Send {
+            device: self.selector(),
+            command: setting,
+        };
+        let event = self.connection.request::<Option<DeviceEvent>>(&request)?;
+        if let Some(event) = &event {
+            self.state.update_self_with_event(event);
+        }
+        Ok(event)
+    }
+
+    fn query(&mut self, command: Command) -> Result<DeviceEvent, DeviceError> {
+        let request = ApiRequest::Send {
+            device: self.selector(),
+            command,
+        };
+        self.connection
+            .request::<Option<DeviceEvent>>(&request)?
+            .ok_or(DeviceError::NoResponse())
+    }
+
+    /// Takes the state the tray knows, the headset isn't queried again.
+    /// Fails with `NoResponse` while the tray can't reach the headset, like querying it directly.
+    fn refresh_state(&mut self) -> Result<(), DeviceError> {
+        let values: StateValues = self.connection.request(&ApiRequest::State {
+            device: self.selector(),
+        })?;
+        let state = &mut self.state;
+        state.battery_level = values.battery_level;
+        state.charging = values.charging;
+        state.muted = values.muted;
+        state.mic_connected = values.mic_connected;
+        state.automatic_shutdown_after = values.automatic_shutdown_after;
+        state.pairing_info = values.pairing_info;
+        state.product_color = values.product_color;
+        state.side_tone_on = values.side_tone_on;
+        state.side_tone_volume = values.side_tone_volume;
+        state.voice_prompt_on = values.voice_prompt_on;
+        state.connected = values.connected;
+        state.time_remaining = values.time_remaining;
+        state.time_to_full = values.time_to_full;
+        if state.connected != Some(true) {
+            return Err(DeviceError::NoResponse());
+        }
+        Ok(())
+    }
+}
+
+/// The values of a serialized `DeviceState`.
+#[derive(Deserialize)]
+struct StateValues {
+    battery_level: Option<u8>,
+    charging: Option<ChargingStatus>,
+    muted: Option<bool>,
+    mic_connected: Option<bool>,
+    #[serde(
+        default,
+        rename = "automatic_shutdown_minutes",
+        deserialize_with = "minutes::deserialize_option"
+    )]
+    automatic_shutdown_after: Option<Duration>,
+    pairing_info: Option<u8>,
+    product_color: Option<Color>,
+    side_tone_on: Option<bool>,
+    side_tone_volume: Option<u8>,
+    voice_prompt_on: Option<bool>,
+    connected: Option<bool>,
+    #[serde(
+        default,
+        rename = "time_remaining_minutes",
+        deserialize_with = "minutes::deserialize_option"
+    )]
+    time_remaining: Option<Duration>,
+    #[serde(
+        default,
+        rename = "time_to_full_minutes",
+        deserialize_with = "minutes::deserialize_option"
+    )]
+    time_to_full: Option<Duration>,
+}
+
+struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    // What was read of a line before a timeout
+    partial: Vec<u8>,
+}
+
+impl Connection {
+    fn open(path: &Path) -> Result<Self, DeviceError> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            partial: Vec::new(),
+        })
+    }
+
+    fn request<T: DeserializeOwned>(&mut self, request: &ApiRequest) -> Result<T, DeviceError> {
+        let mut line = serde_json::to_string(request)
+            .map_err(|e| DeviceError::Daemon(format!("Invalid request: {e}")))?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        self.read(RESPONSE_TIMEOUT)?
+            .ok_or(DeviceError::NoResponse())?
+            .into_result()
+    }
+
+    /// Reads the next response, `Ok(None)` if none arrived within `timeout`.
+    fn read<T: DeserializeOwned>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<ApiResponse<T>>, DeviceError> {
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        match self.reader.read_until(b'\n', &mut self.partial) {
+            Ok(_) if self.partial.ends_with(b"\n") => {
+                let line = std::mem::take(&mut self.partial);
+                serde_json::from_slice(&line)
+                    .map(Some)
+                    .map_err(|e| DeviceError::Daemon(format!("Invalid response: {e}")))
+            }
+            Ok(_) => Err(DeviceError::Daemon(
+                "The tray closed the connection.".to_string(),
+            )),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[test]
+fn test_remote_device() {
+    use crate::daemon::server::Server;
+    use crate::devices::mock::MockCloudIIWireless;
+    use crate::monitor::Monitor;
+
+    let path = std::env::temp_dir().join(format!("hyper_headset_{}.sock", std::process::id()));
+    let server = Server::start(&path).unwrap();
+    let headset = MockCloudIIWireless::new();
+    let mut device = headset.connect().unwrap();
+    let mut monitor = Monitor::new(Duration::from_secs(60));
+    monitor.step(device.as_mut(), Duration::ZERO).unwrap();
+    let info = DeviceInfo {
+        path: "/dev/hidraw0".to_string(),
+        serial_number: Some("ABC123".to_string()),
+        vendor_id: 0x03F0,
+        product_id: 0x018B,
+        product_string: Some("HyperX Cloud II Wireless".to_string()),
+    };
+    let (requests, received) = std::sync::mpsc::channel();
+    server.add(0, ServedDevice::new(info, device.as_ref()), requests);
+    server.update(0, device.get_device_state());
+
+    assert!(connect_to(&path, Some("XYZ")).unwrap().is_none());
+    let client_path = path.clone();
+    let client = std::thread::spawn(move || {
+        let mut remote = connect_to(&client_path, Some("ABC123")).unwrap().unwrap();
+        assert!(remote.capabilities().mute.writable);
+        assert!(!remote.capabilities().voice_prompt.is_supported());
+        remote.refresh_state().unwrap();
+        assert_eq!(remote.get_device_state().battery_level, Some(100));
+        remote.apply(Command::SetMute(true)).unwrap();
+        assert_eq!(
+            remote.query(Command::GetMute).unwrap(),
+            DeviceEvent::Muted(true)
+        );
+        assert!(!remote.toggle_mute().unwrap());
+        assert_eq!(
+            remote.apply(Command::SetSideTone(true)).unwrap(),
+            Some(DeviceEvent::SideToneOn(true))
+        );
+        assert_eq!(remote.get_device_state().side_tone_on, Some(true));
+        assert!(matches!(
+            remote.apply(Command::SetVoicePrompt(true)),
+            Err(DeviceError::NotSupported(Command::SetVoicePrompt(true)))
+        ));
+        remote.next_event(Duration::from_secs(5)).unwrap()
+    });
+    // Plays the device thread of the tray until the client is done.
+    let mut handled = 0;
+    let mut level: u8 = 100;
+    while !client.is_finished() {
+        if let Ok(request) = received.recv_timeout(Duration::from_millis(10)) {
+            let request: crate::monitor::Request = request;
+            let result = monitor.handle(device.as_mut(), request.action);
+            request.reply.unwrap().send(result).unwrap();
+            handled += 1;
+        }
+        // Keeps changing once the client is subscribing, whenever that happens exactly.
+        if handled >= 5 {
+            level = level.saturating_sub(1);
+            device.get_device_state_mut().battery_level = Some(level);
+        }
+        server.update(0, device.get_device_state());
+    }
+    assert!(matches!(
+        client.join().unwrap(),
+        Some(DeviceEvent::BatterLevel(_))
+    ));
+
+    device.get_device_state_mut().connected = None;
+    server.update(0, device.get_device_state());
+    let mut remote = connect_to(&path, None).unwrap().unwrap();
+    assert!(matches!(
+        remote.refresh_state(),
+        Err(DeviceError::NoResponse())
+    ));
+    let _ = std::fs::remove_file(&path);
+}
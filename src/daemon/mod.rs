@@ -0,0 +1,162 @@
+// The line-delimited JSON protocol the tray serves its headsets with, so the CLI can use a headset
+// while the tray holds it open instead of both reading from it.
+pub mod client;
+pub mod server;
+
+use crate::devices::{Command, Device, DeviceError, DeviceEvent, DeviceInfo};
+use serde::{Deserialize, Serialize};
+use std::{mem::discriminant, ops::RangeInclusive, path::PathBuf, time::Duration};
+
+/// `$XDG_RUNTIME_DIR/hyper_headset.sock`, there is no socket without a runtime directory.
+pub fn socket_path() -> Option<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+    Some(PathBuf::from(runtime_dir).join("hyper_headset.sock"))
+}
+
+/// One line sent to the socket, e.g. `{"request": "send", "command": {"set_mute": true}}`.
+/// `device` selects the headset like `DeviceInfo::matches`, without it the first one is used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum ApiRequest {
+    /// Answered with the served headsets, see `ServedDevice`.
+    Devices,
+    /// Answered with the state as printed by `hyper_headset_cli --format json`.
+    State {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+    /// Answered with the value the command read or changed, `null` if it isn't read back.
+    Send {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+        command: Command,
+    },
+    /// Flips the mute, answered with the new value once the headset confirmed it.
+    ToggleMute {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+    /// Answered with `null`, followed by an answer for every value that changes from then on.
+    Subscribe {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+}
+
+/// One line answering a request: `{"ok": ...}`, `{"not_supported": <command>}`,
+/// `{"not_applied": {"expected": <event>, "actual": <event>}}` or `{"error": "..."}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiResponse<T> {
+    Ok(T),
+    NotSupported(Command),
+    NotApplied {
+        expected: DeviceEvent,
+        actual: DeviceEvent,
+    },
+    Error(String),
+}
+
+impl<T> From<Result<T, DeviceError>> for ApiResponse<T> {
+    fn from(result: Result<T, DeviceError>) -> Self {
+        match result {
+            Ok(value) => ApiResponse::Ok(value),
+            Err(DeviceError::NotSupported(command)) => ApiResponse::NotSupported(command),
+            Err(DeviceError::SettingNotApplied { expected, actual }) => {
+                ApiResponse::NotApplied { expected, actual }
+            }
+            Err(error) => ApiResponse::Error(error.to_string()),
+        }
+    }
+}
+
+impl<T> ApiResponse<T> {
+    pub fn into_result(self) -> Result<T, DeviceError> {
+        match self {
+            ApiResponse::Ok(value) => Ok(value),
+            ApiResponse::NotSupported(command) => Err(DeviceError::NotSupported(command)),
+            ApiResponse::NotApplied { expected, actual } => {
+                Err(DeviceError::SettingNotApplied { expected, actual })
+            }
+            ApiResponse::Error(error) => Err(DeviceError::Daemon(error)),
+        }
+    }
+}
+
+/// A headset served by the tray and what it supports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServedDevice {
+    #[serde(flatten)]
+    pub info: DeviceInfo,
+    /// The supported commands, the values of the setters are placeholders.
+    pub commands: Vec<Command>,
+    pub side_tone_volume_range: RangeInclusive<u8>,
+}
+
+impl ServedDevice {
+    pub fn new(info: DeviceInfo, device: &dyn Device) -> Self {
+        let setters = [
+            Command::SetAutomaticShutdown(Duration::ZERO),
+            Command::SetMute(false),
+            Command::SetSideTone(false),
+            Command::SetSideToneVolume(0),
+            Command::SetVoicePrompt(false),
+        ];
+        ServedDevice {
+            info,
+            commands: Command::GETTERS
+                .into_iter()
+                .chain(setters)
+                .filter(|command| device.get_command_packet(*command).is_some())
+                .collect(),
+            side_tone_volume_range: device.side_tone_volume_range(),
+        }
+    }
+
+    /// Whether `command` is supported, regardless of its value.
+    pub fn supports(&self, command: Command) -> bool {
+        self.commands
+            .iter()
+            .any(|supported| discriminant(supported) == discriminant(&command))
+    }
+}
+
+#[test]
+fn test_serialize_requests() {
+    let request: ApiRequest =
+        serde_json::from_str(r#"{"request": "send", "command": {"set_mute": true}}"#).unwrap();
+    assert_eq!(
+        request,
+        ApiRequest::Send {
+            device: None,
+            command: Command::SetMute(true)
+        }
+    );
+    let request = ApiRequest::Send {
+        device: Some("ABC123".to_string()),
+        command: Command::SetAutomaticShutdown(Duration::from_secs(20 * 60)),
+    };
+    assert_eq!(
+        serde_json::to_string(&request).unwrap(),
+        r#"{"request":"send","device":"ABC123","command":{"set_automatic_shutdown_minutes":20}}"#
+    );
+    assert_eq!(
+        serde_json::to_string(&ApiResponse::<()>::NotSupported(Command::GetVoicePrompt)).unwrap(),
+        r#"{"not_supported":"get_voice_prompt"}"#
+    );
+    let response: ApiResponse<()> = serde_json::from_str(
+        r#"{"not_applied": {"expected": {"event": "muted", "value": true}, "actual": {"event": "muted", "value": false}}}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        response.into_result(),
+        Err(DeviceError::SettingNotApplied {
+            expected: DeviceEvent::Muted(true),
+            actual: DeviceEvent::Muted(false),
+        })
+    ));
+    assert_eq!(
+        serde_json::from_str::<ApiRequest>(r#"{"request": "toggle_mute"}"#).unwrap(),
+        ApiRequest::ToggleMute { device: None }
+    );
+}
@@ -0,0 +1,240 @@
+use crate::daemon::{ApiRequest, ApiResponse, ServedDevice};
+use crate::devices::{DeviceError, DeviceEvent, DeviceState};
+use crate::monitor::{Action, Request};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// Long enough for a query with retries and backoff, a request still queued after it is dropped.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves headsets owned by other threads on a Unix socket, every client gets its own thread.
+#[derive(Clone, Default)]
+pub struct Server {
+    devices: Arc<Mutex<BTreeMap<usize, Served>>>,
+}
+
+struct Served {
+    device: ServedDevice,
+    requests: Sender<Request>,
+    state: serde_json::Value,
+    events: Vec<DeviceEvent>,
+    subscribers: Vec<Sender<DeviceEvent>>,
+}
+
+impl Server {
+    /// Listens on `path`, a socket left behind by a crashed tray is replaced.
+    pub fn start(path: &Path) -> Result<Self, DeviceError> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(DeviceError::Daemon(format!(
+                "{} is already in use, is the tray running twice?",
+                path.display()
+            )));
+        }
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        let listener = UnixListener::bind(path)?;
+        let server = Server::default();
+        let clients = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = clients.clone();
+                std::thread::spawn(move || server.serve_client(stream));
+            }
+        });
+        Ok(server)
+    }
+
+    /// Serves a device, its commands are forwarded to `requests`.
+    /// Adding it again after a reconnect keeps the subscriptions.
+    pub fn add(&self, id: usize, device: ServedDevice, requests: Sender<Request>) {
+        let mut devices = self.devices.lock().unwrap();
+        match devices.get_mut(&id) {
+            Some(served) => {
+                served.device = device;
+                served.requests = requests;
+            }
+            None => {
+                devices.insert(
+                    id,
+                    Served {
+                        device,
+                        requests,
+                        state: serde_json::Value::Null,
+                        events: Vec::new(),
+                        subscribers: Vec::new(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Remembers `state` for `state` requests and sends the values that changed to the subscribers.
+    pub fn update(&self, id: usize, state: &DeviceState) {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(served) = devices.get_mut(&id) else {
+            return;
+        };
+        served.state = serde_json::to_value(state).unwrap_or_default();
+        let events = state.events();
+        for event in events.iter().filter(|event| !served.events.contains(event)) {
+            served
+                .subscribers
+                .retain(|subscriber| subscriber.send(*event).is_ok());
+        }
+        served.events = events;
+    }
+
+    /// Stops serving a device, which ends its subscriptions.
+    pub fn remove(&self, id: usize) {
+        self.devices.lock().unwrap().remove(&id);
+    }
+
+    /// Runs `f` on the device matching `selector`, or the first one without a selector.
+    fn with_device<T>(
+        &self,
+        selector: Option<&str>,
+        f: impl FnOnce(&mut Served) -> T,
+    ) -> Result<T, DeviceError> {
+        let mut devices = self.devices.lock().unwrap();
+        devices
+            .values_mut()
+            .find(|served| selector.is_none_or(|selector| served.device.info.matches(selector)))
+            .map(f)
+            .ok_or(DeviceError::NoDeviceFound())
+    }
+
+    fn serve_client(&self, stream: UnixStream) -> Result<(), DeviceError> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    let response = ApiResponse::<()>::Error(format!("Invalid request: {e}"));
+                    write_line(&mut writer, &response)?;
+                    continue;
+                }
+            };
+            match request {
+                ApiRequest::Devices => {
+                    let devices: Vec<ServedDevice> = self
+                        .devices
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .map(|served| served.device.clone())
+                        .collect();
+                    write_line(&mut writer, &ApiResponse::Ok(devices))?;
+                }
+                ApiRequest::State { device } => {
+                    let state = self.with_device(device.as_deref(), |served| served.state.clone());
+                    write_line(&mut writer, &ApiResponse::from(state))?;
+                }
+                ApiRequest::Send { device, command } => {
+                    let result = self
+                        .with_device(device.as_deref(), |served| served.requests.clone())
+                        .and_then(|requests| forward(&requests, command.into()));
+                    write_line(&mut writer, &ApiResponse::from(result))?;
+                }
+                ApiRequest::ToggleMute { device } => {
+                    let result = self
+                        .with_device(device.as_deref(), |served| served.requests.clone())
+                        .and_then(|requests| forward(&requests, Action::ToggleMute));
+                    write_line(&mut writer, &ApiResponse::from(result))?;
+                }
+                ApiRequest::Subscribe { device } => {
+                    let (subscriber, events) = mpsc::channel();
+                    let subscribed = self.with_device(device.as_deref(), |served| {
+                        served.subscribers.push(subscriber)
+                    });
+                    let failed = subscribed.is_err();
+                    write_line(&mut writer, &ApiResponse::from(subscribed))?;
+                    if failed {
+                        continue;
+                    }
+                    // The connection only carries events from now on, until the device is removed.
+                    for event in events {
+                        write_line(&mut writer, &ApiResponse::Ok(event))?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hands `action` to the thread owning the device and waits for the result.
+fn forward(requests: &Sender<Request>, action: Action) -> Result<Option<DeviceEvent>, DeviceError> {
+    let (reply, result) = mpsc::channel();
+    requests
+        .send(Request {
+            action,
+            reply: Some(reply),
+            deadline: Some(Instant::now() + REPLY_TIMEOUT),
+        })
+        .map_err(|_| DeviceError::Daemon("The headset is gone.".to_string()))?;
+    // Once the device thread picked the request up it runs, so the answer is always waited for.
+    result
+        .recv()
+        .map_err(|_| DeviceError::Daemon("The headset is gone.".to_string()))?
+}
+
+fn write_line(writer: &mut impl Write, response: &impl Serialize) -> Result<(), DeviceError> {
+    let mut line = serde_json::to_string(response)
+        .map_err(|e| DeviceError::Daemon(format!("Invalid response: {e}")))?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn test_subscribers_get_changes() {
+    use crate::devices::{descriptor::built_in_descriptors, generic::GenericDevice, Device};
+
+    let state = DeviceState::without_transport(0x018B, 0x03F0);
+    let mut device = GenericDevice::new(built_in_descriptors()[0].clone(), state).unwrap();
+    let info = crate::devices::DeviceInfo {
+        path: "/dev/hidraw0".to_string(),
+        serial_number: None,
+        vendor_id: 0x03F0,
+        product_id: 0x018B,
+        product_string: None,
+    };
+    let server = Server::default();
+    let (requests, _) = mpsc::channel();
+    server.add(0, ServedDevice::new(info, &device), requests);
+    server.update(0, device.get_device_state());
+
+    let (subscriber, events) = mpsc::channel();
+    server
+        .with_device(Some("/dev/hidraw0"), |served| {
+            served.subscribers.push(subscriber)
+        })
+        .unwrap();
+    server.update(0, device.get_device_state());
+    assert!(events.try_recv().is_err());
+
+    device.get_device_state_mut().muted = Some(true);
+    server.update(0, device.get_device_state());
+    assert_eq!(events.try_recv().unwrap(), DeviceEvent::Muted(true));
+    assert!(events.try_recv().is_err());
+
+    server.remove(0);
+    assert!(events.recv().is_err());
+}
@@ -0,0 +1,525 @@
+use dbus::arg::AppendAll;
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    PropertiesPropertiesChanged, RequestNameReply,
+};
+use dbus::blocking::Connection;
+use dbus::message::{MessageType, SignalArgs};
+use dbus::{Message, MethodErr, Path};
+use dbus_crossroads::{Context, Crossroads, IfaceToken};
+use hyper_headset::devices::{
+    Capabilities, ChargingStatus, Command, DeviceError, DeviceEvent, DeviceState,
+};
+use hyper_headset::monitor::{Action, Request};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    ops::RangeInclusive,
+    sync::mpsc::{self, Receiver, Sender},
+    time::Duration,
+};
+
+const BUS_NAME: &str = "org.hyperheadset";
+const INTERFACE: &str = "org.hyperheadset.Device";
+const MANAGER_PATH: &str = "/org/hyperheadset";
+const MANAGER_INTERFACE: &str = "org.hyperheadset.Manager";
+const NOT_SUPPORTED: &str = "org.hyperheadset.Error.NotSupported";
+const NOT_APPLIED: &str = "org.hyperheadset.Error.NotApplied";
+const INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Publishes every headset as `/org/hyperheadset/Device<id>` on the session bus.
+/// `/org/hyperheadset` acts on the first headset, for shortcuts that can't know the id.
+#[derive(Clone)]
+pub struct DbusService {
+    updates: Sender<Update>,
+}
+
+enum Update {
+    Added(usize, Sender<Request>),
+    Connected(usize, RangeInclusive<u8>),
+    Changed(usize, Properties),
+    Removed(usize),
+}
+
+impl DbusService {
+    /// Connects to the session bus and serves method calls from its own thread.
+    pub fn start() -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::new_session()?;
+        if connection.request_name(BUS_NAME, false, false, true)? != RequestNameReply::PrimaryOwner
+        {
+            return Err(format!("{BUS_NAME} is already taken, is the tray running twice?").into());
+        }
+        let (updates, receiver) = mpsc::channel();
+        std::thread::spawn(move || serve(connection, receiver));
+        Ok(DbusService { updates })
+    }
+
+    /// Publishes a device, its setters are forwarded to `requests`.
+    pub fn add(&self, id: usize, requests: Sender<Request>) {
+        let _ = self.updates.send(Update::Added(id, requests));
+    }
+
+    /// Setters are checked against `capabilities` of the connected headset.
+    pub fn set_capabilities(&self, id: usize, capabilities: &Capabilities) {
+        let range = capabilities.side_tone_volume_range.clone();
+        let _ = self.updates.send(Update::Connected(id, range));
+    }
+
+    pub fn update(&self, id: usize, state: &DeviceState) {
+        let _ = self
+            .updates
+            .send(Update::Changed(id, Properties::from(state)));
+    }
+
+    pub fn remove(&self, id: usize) {
+        let _ = self.updates.send(Update::Removed(id));
+    }
+}
+
+/// The published state, unknown numbers are -1, unknown flags false and an unknown charging status empty.
+#[derive(Debug, Clone, PartialEq)]
+struct Properties {
+    name: String,
+    battery_level: i32,
+    charging: String,
+    muted: bool,
+    side_tone: bool,
+    side_tone_volume: i32,
+    auto_shutdown: i32,
+    connected: bool,
+    time_remaining: i32,
+    time_to_full: i32,
+}
+
+impl Default for Properties {
+    fn default() -> Self {
+        Properties {
+            name: String::new(),
+            battery_level: -1,
+            charging: String::new(),
+            muted: false,
+            side_tone: false,
+            side_tone_volume: -1,
+            auto_shutdown: -1,
+            connected: false,
+            time_remaining: -1,
+            time_to_full: -1,
+        }
+    }
+}
+
+impl From<&DeviceState> for Properties {
+    fn from(state: &DeviceState) -> Self {
+        Properties {
+            name: state.device_name.clone().unwrap_or_default(),
+            battery_level: state.battery_level.map_or(-1, i32::from),
+            charging: state.charging.map_or("", charging_name).to_string(),
+            muted: state.muted == Some(true),
+            side_tone: state.side_tone_on == Some(true),
+            side_tone_volume: state.side_tone_volume.map_or(-1, i32::from),
+            auto_shutdown: state
+                .automatic_shutdown_after
+                .map_or(-1, |after| (after.as_secs() / 60) as i32),
+            connected: state.connected == Some(true),
+            time_remaining: state
+                .time_remaining
+                .map_or(-1, |remaining| (remaining.as_secs() / 60) as i32),
+            time_to_full: state
+                .time_to_full
+                .map_or(-1, |to_full| (to_full.as_secs() / 60) as i32),
+        }
+    }
+}
+
+/// The same names the CLI prints with `--format json`.
+fn charging_name(status: ChargingStatus) -> &'static str {
+    match status {
+        ChargingStatus::NotCharging => "not_charging",
+        ChargingStatus::Charging => "charging",
+        ChargingStatus::FullyCharged => "fully_charged",
+        ChargingStatus::ChargeError => "charge_error",
+    }
+}
+
+impl Properties {
+    /// The properties that differ from `previous`, as sent with `PropertiesChanged`.
+    fn changed_since(&self, previous: &Properties) -> PropMap {
+        let mut changed = PropMap::new();
+        let mut insert = |name: &str, value: Box<dyn RefArg>, differs: bool| {
+            if differs {
+                changed.insert(name.to_string(), Variant(value));
+            }
+        };
+        insert(
+            "Name",
+            Box::new(self.name.clone()),
+            self.name != previous.name,
+        );
+        insert(
+            "BatteryLevel",
+            Box::new(self.battery_level),
+            self.battery_level != previous.battery_level,
+        );
+        insert(
+            "Charging",
+            Box::new(self.charging.clone()),
+            self.charging != previous.charging,
+        );
+        insert("Muted", Box::new(self.muted), self.muted != previous.muted);
+        insert(
+            "SideTone",
+            Box::new(self.side_tone),
+            self.side_tone != previous.side_tone,
+        );
+        insert(
+            "SideToneVolume",
+            Box::new(self.side_tone_volume),
+            self.side_tone_volume != previous.side_tone_volume,
+        );
+        insert(
+            "AutoShutdown",
+            Box::new(self.auto_shutdown),
+            self.auto_shutdown != previous.auto_shutdown,
+        );
+        insert(
+            "Connected",
+            Box::new(self.connected),
+            self.connected != previous.connected,
+        );
+        insert(
+            "TimeRemaining",
+            Box::new(self.time_remaining),
+            self.time_remaining != previous.time_remaining,
+        );
+        insert(
+            "TimeToFull",
+            Box::new(self.time_to_full),
+            self.time_to_full != previous.time_to_full,
+        );
+        changed
+    }
+}
+
+struct DeviceObject {
+    properties: Properties,
+    requests: Sender<Request>,
+    replies: Sender<Message>,
+    /// Unknown until the headset connected.
+    side_tone_volume_range: RangeInclusive<u8>,
+}
+
+impl DeviceObject {
+    fn request(&self, ctx: Context, command: Command) -> Option<Context> {
+        request(ctx, &self.requests, command.into(), &self.replies, |_| {
+            Ok(())
+        })
+    }
+}
+
+/// Calls `call` with the `DeviceObject` the method was called on.
+fn with_object(
+    mut ctx: Context,
+    crossroads: &mut Crossroads,
+    call: impl FnOnce(Context, &DeviceObject) -> Option<Context>,
+) -> Option<Context> {
+    match crossroads.data_mut::<DeviceObject>(ctx.path()) {
+        Some(object) => call(ctx, object),
+        None => {
+            let error = MethodErr::no_path(ctx.path());
+            ctx.reply(Err::<(), _>(error));
+            Some(ctx)
+        }
+    }
+}
+
+/// Hands `action` to the thread owning the device, in the order the calls came in,
+/// and waits for the result on a thread of its own so the bus keeps serving meanwhile.
+/// `answer` turns the event into the reply, which goes to `replies` for `serve` to send.
+fn request<OA, F>(
+    mut ctx: Context,
+    requests: &Sender<Request>,
+    action: Action,
+    replies: &Sender<Message>,
+    answer: F,
+) -> Option<Context>
+where
+    OA: AppendAll + Send + 'static,
+    F: FnOnce(Option<DeviceEvent>) -> Result<OA, MethodErr> + Send + 'static,
+{
+    let (reply, result) = mpsc::channel();
+    let request = Request {
+        action,
+        reply: Some(reply),
+        deadline: None,
+    };
+    if requests.send(request).is_err() {
+        ctx.reply(Err::<OA, _>(MethodErr::failed("The headset is gone")));
+        return Some(ctx);
+    }
+    let replies = replies.clone();
+    std::thread::spawn(move || {
+        let answer = match result.recv() {
+            Ok(Ok(event)) => answer(event),
+            Ok(Err(error)) => Err(method_error(error)),
+            Err(_) => Err(MethodErr::failed("The headset is gone")),
+        };
+        let call = ctx.message();
+        if !call.get_no_reply() {
+            let _ = replies.send(match answer {
+                Ok(values) => call.return_with_args(values),
+                Err(error) => error.to_message(call),
+            });
+        }
+    });
+    None
+}
+
+fn method_error(error: DeviceError) -> MethodErr {
+    match error {
+        DeviceError::NotSupported(_) => MethodErr::from((NOT_SUPPORTED, error.to_string())),
+        DeviceError::SettingNotApplied { .. } => MethodErr::from((NOT_APPLIED, error.to_string())),
+        error => MethodErr::failed(&error),
+    }
+}
+
+/// Rejects side tone volumes the headset doesn't support, like the CLI does.
+fn check_range(range: &RangeInclusive<u8>, volume: u8) -> Result<(), MethodErr> {
+    if range.contains(&volume) {
+        return Ok(());
+    }
+    let message = format!(
+        "The side tone volume has to be between {} and {}",
+        range.start(),
+        range.end()
+    );
+    Err(MethodErr::from((INVALID_ARGS, message)))
+}
+
+/// Replies with the new value once the headset confirmed it.
+fn toggle_mute(
+    ctx: Context,
+    requests: &Sender<Request>,
+    replies: &Sender<Message>,
+) -> Option<Context> {
+    request(
+        ctx,
+        requests,
+        Action::ToggleMute,
+        replies,
+        |event| match event {
+            Some(DeviceEvent::Muted(muted)) => Ok((muted,)),
+            _ => Err(MethodErr::failed("The headset didn't confirm the mute")),
+        },
+    )
+}
+
+fn path(id: usize) -> Path<'static> {
+    Path::from(format!("/org/hyperheadset/Device{id}"))
+}
+
+fn register_interface(crossroads: &mut Crossroads) -> IfaceToken<DeviceObject> {
+    crossroads.register(INTERFACE, |b| {
+        b.property("Name")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.name.clone()));
+        b.property("BatteryLevel")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.battery_level));
+        b.property("Charging")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.charging.clone()));
+        b.property("Muted")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.muted));
+        b.property("SideTone")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.side_tone));
+        b.property("SideToneVolume")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.side_tone_volume));
+        b.property("AutoShutdown")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.auto_shutdown));
+        b.property("Connected")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.connected));
+        b.property("TimeRemaining")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.time_remaining));
+        b.property("TimeToFull")
+            .get(|_, object: &mut DeviceObject| Ok(object.properties.time_to_full));
+        // The setters reply once the headset is done, see `request`.
+        b.method_with_cr_custom::<(bool,), (), _, _>(
+            "SetMuted",
+            ("muted",),
+            (),
+            |ctx, cr, (muted,)| {
+                with_object(ctx, cr, |ctx, object| {
+                    object.request(ctx, Command::SetMute(muted))
+                })
+            },
+        );
+        b.method_with_cr_custom::<(), (bool,), _, _>(
+            "ToggleMute",
+            (),
+            ("muted",),
+            |ctx, cr, ()| {
+                with_object(ctx, cr, |ctx, object| {
+                    toggle_mute(ctx, &object.requests, &object.replies)
+                })
+            },
+        );
+        b.method_with_cr_custom::<(bool,), (), _, _>(
+            "SetSideTone",
+            ("on",),
+            (),
+            |ctx, cr, (on,)| {
+                with_object(ctx, cr, |ctx, object| {
+                    object.request(ctx, Command::SetSideTone(on))
+                })
+            },
+        );
+        b.method_with_cr_custom::<(u8,), (), _, _>(
+            "SetSideToneVolume",
+            ("volume",),
+            (),
+            |ctx, cr, (volume,)| {
+                with_object(ctx, cr, |mut ctx, object| {
+                    if let Err(error) = check_range(&object.side_tone_volume_range, volume) {
+                        ctx.reply(Err::<(), _>(error));
+                        return Some(ctx);
+                    }
+                    object.request(ctx, Command::SetSideToneVolume(volume))
+                })
+            },
+        );
+        b.method_with_cr_custom::<(u8,), (), _, _>(
+            "SetAutoShutdown",
+            ("minutes",),
+            (),
+            |ctx, cr, (minutes,)| {
+                with_object(ctx, cr, |ctx, object| {
+                    let after = Duration::from_secs(minutes as u64 * 60);
+                    object.request(ctx, Command::SetAutomaticShutdown(after))
+                })
+            },
+        );
+    })
+}
+
+/// The devices by id, so the first one can be picked.
+type Devices = BTreeMap<usize, Sender<Request>>;
+
+fn insert_manager(crossroads: &mut Crossroads, replies: Sender<Message>) {
+    let token = crossroads.register(MANAGER_INTERFACE, |b| {
+        b.method_with_cr_custom::<(), (bool,), _, _>(
+            "ToggleMute",
+            (),
+            ("muted",),
+            move |mut ctx, cr, ()| {
+                let requests = cr
+                    .data_mut::<Devices>(ctx.path())
+                    .and_then(|devices| devices.values().next().cloned());
+                match requests {
+                    Some(requests) => toggle_mute(ctx, &requests, &replies),
+                    None => {
+                        ctx.reply(Err::<(bool,), _>(MethodErr::failed(
+                            "No headset is connected",
+                        )));
+                        Some(ctx)
+                    }
+                }
+            },
+        );
+    });
+    crossroads.insert(MANAGER_PATH, &[token], Devices::new());
+}
+
+fn serve(connection: Connection, updates: Receiver<Update>) {
+    let mut crossroads = Crossroads::new();
+    let interface = register_interface(&mut crossroads);
+    let (replies, finished) = mpsc::channel();
+    insert_manager(&mut crossroads, replies.clone());
+    loop {
+        if connection
+            .channel()
+            .read_write(Some(POLL_INTERVAL))
+            .is_err()
+        {
+            eprintln!("Lost the connection to the session bus");
+            return;
+        }
+        while let Some(message) = connection.channel().pop_message() {
+            if message.msg_type() == MessageType::MethodCall {
+                let _ = crossroads.handle_message(message, &connection);
+            }
+        }
+        for reply in finished.try_iter() {
+            let _ = connection.channel().send(reply);
+        }
+        for update in updates.try_iter() {
+            match update {
+                Update::Added(id, requests) => {
+                    if let Some(devices) = crossroads.data_mut::<Devices>(&MANAGER_PATH.into()) {
+                        devices.insert(id, requests.clone());
+                    }
+                    let object = DeviceObject {
+                        properties: Properties::default(),
+                        requests,
+                        replies: replies.clone(),
+                        side_tone_volume_range: 0..=u8::MAX,
+                    };
+                    crossroads.insert(path(id), &[interface], object);
+                }
+                Update::Connected(id, range) => {
+                    if let Some(object) = crossroads.data_mut::<DeviceObject>(&path(id)) {
+                        object.side_tone_volume_range = range;
+                    }
+                }
+                Update::Changed(id, properties) => {
+                    if let Some(object) = crossroads.data_mut::<DeviceObject>(&path(id)) {
+                        let changed = properties.changed_since(&object.properties);
+                        object.properties = properties;
+                        emit_changed(&connection, path(id), INTERFACE, changed);
+                    }
+                }
+                Update::Removed(id) => {
+                    if let Some(devices) = crossroads.data_mut::<Devices>(&MANAGER_PATH.into()) {
+                        devices.remove(&id);
+                    }
+                    crossroads.remove::<DeviceObject>(&path(id));
+                }
+            }
+        }
+    }
+}
+
+fn emit_changed(connection: &Connection, path: Path<'static>, interface: &str, changed: PropMap) {
+    if changed.is_empty() {
+        return;
+    }
+    let signal = PropertiesPropertiesChanged {
+        interface_name: interface.to_string(),
+        changed_properties: changed,
+        invalidated_properties: Vec::new(),
+    };
+    let _ = connection.channel().send(signal.to_emit_message(&path));
+}
+
+#[test]
+fn test_changed_properties() {
+    let mut properties = Properties::default();
+    assert!(properties.changed_since(&Properties::default()).is_empty());
+    properties.muted = true;
+    properties.battery_level = 50;
+    let changed = properties.changed_since(&Properties::default());
+    let mut names: Vec<&str> = changed.keys().map(String::as_str).collect();
+    names.sort();
+    assert_eq!(names, ["BatteryLevel", "Muted"]);
+    assert_eq!(changed["BatteryLevel"].0.as_i64(), Some(50));
+}
+
+#[test]
+fn test_side_tone_volume_out_of_range() {
+    assert!(check_range(&(2..=5), 2).is_ok());
+    assert!(check_range(&(2..=5), 5).is_ok());
+    let error = check_range(&(2..=5), 6).unwrap_err();
+    assert_eq!(error.errorname(), INVALID_ARGS);
+    assert_eq!(
+        error.description(),
+        "The side tone volume has to be between 2 and 5"
+    );
+    assert!(check_range(&(2..=5), 1).is_err());
+}
@@ -0,0 +1,283 @@
+use crate::config::config_dir;
+use crate::devices::{Command, DeviceError};
+use serde::Deserialize;
+use std::{collections::BTreeMap, ops::RangeInclusive, path::Path, path::PathBuf};
+
+const BUILT_IN_DESCRIPTORS: [&str; 1] = [include_str!("descriptors/cloud_ii_wireless.toml")];
+
+/// Describes how to talk to a headset model, see `descriptors/cloud_ii_wireless.toml` for an example.
+/// Loaded from TOML and driven by `GenericDevice`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceDescriptor {
+    pub name: String,
+    /// Product strings reported by the dongle, used to pick the descriptor for an opened device.
+    pub product_strings: Vec<String>,
+    pub vendor_ids: Vec<u16>,
+    pub product_ids: Vec<u16>,
+    pub packets: BTreeMap<String, PacketLayout>,
+    pub responses: BTreeMap<String, ResponseLayout>,
+    pub commands: Commands,
+}
+
+/// The layout of the packets sent to the device.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PacketLayout {
+    /// Dongles with one of these vendor IDs use this layout by default.
+    /// Layouts without vendor IDs are only used by commands naming them.
+    #[serde(default)]
+    pub vendor_ids: Vec<u16>,
+    pub length: usize,
+    /// The first bytes of every packet, the rest is filled with zeros.
+    pub base: Vec<u8>,
+    /// Offset of the command ID, the value follows directly after it.
+    pub command_offset: usize,
+    /// Name of the response layout the device answers with.
+    pub response: String,
+}
+
+/// The layout of the responses read from the device.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseLayout {
+    /// Responses not starting with this header are ignored.
+    pub header: Vec<u8>,
+    pub command_offset: usize,
+    pub value_offset: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandDescriptor {
+    pub id: u8,
+    /// Name of the packet layout, defaults to the one matching the vendor ID of the dongle.
+    pub packet: Option<String>,
+    /// Offset of the value in the response, overrides the offset of the response layout.
+    pub value_offset: Option<usize>,
+    /// Values that mean on for on/off settings, `[1]` by default.
+    pub true_values: Option<Vec<u8>>,
+    /// Seconds per step of the value, only used by the automatic shutdown.
+    pub seconds_per_step: Option<u64>,
+    /// Smallest and largest accepted value, only used by the side tone volume.
+    pub range: Option<[u8; 2]>,
+}
+
+impl CommandDescriptor {
+    pub fn is_true(&self, value: u8) -> bool {
+        self.true_values
+            .as_ref()
+            .map_or(value == 1, |values| values.contains(&value))
+    }
+
+    pub fn seconds_per_step(&self) -> u64 {
+        self.seconds_per_step.unwrap_or(1).max(1)
+    }
+
+    pub fn range(&self) -> RangeInclusive<u8> {
+        self.range.map_or(0..=u8::MAX, |[min, max]| min..=max)
+    }
+}
+
+/// The commands understood by the device, commands that are left out are not supported.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Commands {
+    pub get_wireless_connected: Option<CommandDescriptor>,
+    pub get_battery: Option<CommandDescriptor>,
+    pub get_charging: Option<CommandDescriptor>,
+    pub get_automatic_shutdown: Option<CommandDescriptor>,
+    pub set_automatic_shutdown: Option<CommandDescriptor>,
+    pub get_mute: Option<CommandDescriptor>,
+    pub set_mute: Option<CommandDescriptor>,
+    pub get_mic_connected: Option<CommandDescriptor>,
+    pub get_pairing_info: Option<CommandDescriptor>,
+    pub get_product_color: Option<CommandDescriptor>,
+    pub get_side_tone: Option<CommandDescriptor>,
+    pub set_side_tone: Option<CommandDescriptor>,
+    pub get_side_tone_volume: Option<CommandDescriptor>,
+    pub set_side_tone_volume: Option<CommandDescriptor>,
+    pub get_voice_prompt: Option<CommandDescriptor>,
+    pub set_voice_prompt: Option<CommandDescriptor>,
+}
+
+impl Commands {
+    pub fn get(&self, command: Command) -> Option<&CommandDescriptor> {
+        match command {
+            Command::GetWirelessConnected => self.get_wireless_connected.as_ref(),
+            Command::GetBattery => self.get_battery.as_ref(),
+            Command::GetCharging => self.get_charging.as_ref(),
+            Command::GetAutomaticShutdown => self.get_automatic_shutdown.as_ref(),
+            Command::SetAutomaticShutdown(_) => self.set_automatic_shutdown.as_ref(),
+            Command::GetMute => self.get_mute.as_ref(),
+            Command::SetMute(_) => self.set_mute.as_ref(),
+            Command::GetMicConnected => self.get_mic_connected.as_ref(),
+            Command::GetPairingInfo => self.get_pairing_info.as_ref(),
+            Command::GetProductColor => self.get_product_color.as_ref(),
+            Command::GetSideTone => self.get_side_tone.as_ref(),
+            Command::SetSideTone(_) => self.set_side_tone.as_ref(),
+            Command::GetSideToneVolume => self.get_side_tone_volume.as_ref(),
+            Command::SetSideToneVolume(_) => self.set_side_tone_volume.as_ref(),
+            Command::GetVoicePrompt => self.get_voice_prompt.as_ref(),
+            Command::SetVoicePrompt(_) => self.set_voice_prompt.as_ref(),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &CommandDescriptor> {
+        [
+            &self.get_wireless_connected,
+            &self.get_battery,
+            &self.get_charging,
+            &self.get_automatic_shutdown,
+            &self.set_automatic_shutdown,
+            &self.get_mute,
+            &self.set_mute,
+            &self.get_mic_connected,
+            &self.get_pairing_info,
+            &self.get_product_color,
+            &self.get_side_tone,
+            &self.set_side_tone,
+            &self.get_side_tone_volume,
+            &self.set_side_tone_volume,
+            &self.get_voice_prompt,
+            &self.set_voice_prompt,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+impl DeviceDescriptor {
+    pub fn from_toml(descriptor: &str) -> Result<Self, DeviceError> {
+        let descriptor: DeviceDescriptor = toml::from_str(descriptor)
+            .map_err(|e| DeviceError::InvalidDescriptor(e.message().to_string()))?;
+        descriptor.validate().map_err(|e| {
+            DeviceError::InvalidDescriptor(format!("{}: {e}", descriptor.name))
+        })?;
+        Ok(descriptor)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, DeviceError> {
+        DeviceDescriptor::from_toml(&std::fs::read_to_string(path)?).map_err(|e| match e {
+            DeviceError::InvalidDescriptor(e) => {
+                DeviceError::InvalidDescriptor(format!("{}: {e}", path.display()))
+            }
+            e => e,
+        })
+    }
+
+    /// Whether this descriptor is meant for the dongle with these IDs.
+    pub fn supports(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.vendor_ids.contains(&vendor_id) && self.product_ids.contains(&product_id)
+    }
+
+    /// The packet layout used by default for dongles with `vendor_id`.
+    pub fn packet_for_vendor_id(&self, vendor_id: u16) -> Option<&str> {
+        self.packets
+            .iter()
+            .find(|(_, packet)| packet.vendor_ids.contains(&vendor_id))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Catches mistakes that would otherwise only show up as out of bounds accesses or missing responses.
+    fn validate(&self) -> Result<(), String> {
+        for vendor_id in &self.vendor_ids {
+            if self.packet_for_vendor_id(*vendor_id).is_none() {
+                return Err(format!("no packet layout for vendor ID {vendor_id:04x}"));
+            }
+        }
+        for (name, packet) in &self.packets {
+            if packet.base.len() > packet.length || packet.command_offset + 1 >= packet.length {
+                return Err(format!("packet layout {name} doesn't fit into {} bytes", packet.length));
+            }
+            if !self.responses.contains_key(&packet.response) {
+                return Err(format!("unknown response layout {}", packet.response));
+            }
+        }
+        for (name, response) in &self.responses {
+            if response.header.is_empty() {
+                return Err(format!("response layout {name} has no header"));
+            }
+        }
+        for command in self.commands.all() {
+            if let Some(packet) = &command.packet {
+                if !self.packets.contains_key(packet) {
+                    return Err(format!("unknown packet layout {packet}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn built_in_descriptors() -> Vec<DeviceDescriptor> {
+    BUILT_IN_DESCRIPTORS
+        .iter()
+        .map(|descriptor| {
+            DeviceDescriptor::from_toml(descriptor).expect("built-in descriptors are valid")
+        })
+        .collect()
+}
+
+/// `$XDG_CONFIG_HOME/hyper_headset/devices`, falling back to `~/.config/hyper_headset/devices`.
+pub fn user_descriptor_dir() -> Option<PathBuf> {
+    Some(config_dir()?.join("devices"))
+}
+
+/// All descriptors in `user_descriptor_dir` followed by the built-in ones,
+/// so a user descriptor takes precedence over a built-in one for the same device.
+/// Invalid files are reported and skipped.
+pub fn load_descriptors() -> Vec<DeviceDescriptor> {
+    let mut paths: Vec<PathBuf> = user_descriptor_dir()
+        .and_then(|dir| std::fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "toml"))
+        .collect();
+    paths.sort();
+    let mut descriptors: Vec<DeviceDescriptor> = paths
+        .iter()
+        .filter_map(|path| match DeviceDescriptor::load(path) {
+            Ok(descriptor) => Some(descriptor),
+            Err(e) => {
+                eprintln!("Skipping device descriptor: {e}");
+                None
+            }
+        })
+        .collect();
+    descriptors.extend(built_in_descriptors());
+    descriptors
+}
+
+#[test]
+fn test_invalid_descriptors_are_rejected() {
+    let descriptor = r#"
+        name = "Test headset"
+        product_strings = ["Test headset"]
+        vendor_ids = [0x0001]
+        product_ids = [0x0002]
+
+        [packets.main]
+        vendor_ids = [0x0001]
+        length = 8
+        base = [0x01]
+        command_offset = 1
+        response = "main"
+
+        [responses.main]
+        header = [0x01]
+        command_offset = 1
+        value_offset = 2
+
+        [commands]
+        get_battery = { id = 1 }
+    "#;
+    assert!(DeviceDescriptor::from_toml(descriptor).is_ok());
+    let unknown_packet = descriptor.replace("{ id = 1 }", r#"{ id = 1, packet = "other" }"#);
+    assert!(DeviceDescriptor::from_toml(&unknown_packet).is_err());
+    let unknown_command = descriptor.replace("get_battery", "get_volume");
+    assert!(DeviceDescriptor::from_toml(&unknown_command).is_err());
+    let too_short = descriptor.replace("length = 8", "length = 2");
+    assert!(DeviceDescriptor::from_toml(&too_short).is_err());
+}
@@ -0,0 +1,307 @@
+use crate::devices::descriptor::{CommandDescriptor, DeviceDescriptor};
+use crate::devices::{
+    ChargingStatus, Color, Command, Device, DeviceError, DeviceEvent, DeviceState,
+};
+use std::{ops::RangeInclusive, time::Duration};
+
+/// A device whose protocol is described by a `DeviceDescriptor`.
+pub struct GenericDevice {
+    state: DeviceState,
+    descriptor: DeviceDescriptor,
+    /// Name of the packet layout used for commands that don't name one.
+    packet: String,
+}
+
+impl GenericDevice {
+    pub fn new(descriptor: DeviceDescriptor, state: DeviceState) -> Result<Self, DeviceError> {
+        let packet = descriptor
+            .packet_for_vendor_id(state.vendor_id)
+            .ok_or_else(|| {
+                DeviceError::InvalidDescriptor(format!(
+                    "{}: no packet layout for vendor ID {:04x}",
+                    descriptor.name, state.vendor_id
+                ))
+            })?
+            .to_string();
+        Ok(GenericDevice {
+            state,
+            descriptor,
+            packet,
+        })
+    }
+
+    pub fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+
+    fn packet_name<'a>(&'a self, command: &'a CommandDescriptor) -> &'a str {
+        command.packet.as_deref().unwrap_or(&self.packet)
+    }
+
+    fn encode(&self, command: Command) -> Option<Vec<u8>> {
+        let spec = self.descriptor.commands.get(command)?;
+        let layout = &self.descriptor.packets[self.packet_name(spec)];
+        let value = match command {
+            Command::SetAutomaticShutdown(after) => shutdown_steps(spec, after),
+            Command::SetMute(on) | Command::SetSideTone(on) | Command::SetVoicePrompt(on) => {
+                on as u8
+            }
+            Command::SetSideToneVolume(volume) => volume,
+            _ => 0,
+        };
+        let mut packet = layout.base.clone();
+        packet.resize(layout.length, 0);
+        let offset = layout.command_offset;
+        (packet[offset], packet[offset + 1]) = (spec.id, value);
+        Some(packet)
+    }
+
+    fn decode(&self, response: &[u8]) -> Option<DeviceEvent> {
+        let (name, layout) = self
+            .descriptor
+            .responses
+            .iter()
+            .find(|(_, layout)| response.starts_with(&layout.header))?;
+        let id = *response.get(layout.command_offset)?;
+        // Several commands may share an ID as long as they are answered with different response layouts.
+        let (command, spec) = Command::GETTERS.iter().find_map(|command| {
+            let spec = self.descriptor.commands.get(*command)?;
+            let packet = &self.descriptor.packets[self.packet_name(spec)];
+            (spec.id == id && packet.response == *name).then_some((*command, spec))
+        })?;
+        let value = *response.get(spec.value_offset.unwrap_or(layout.value_offset))?;
+        let event = match command {
+            Command::GetWirelessConnected => DeviceEvent::WirelessConnected(spec.is_true(value)),
+            Command::GetBattery => DeviceEvent::BatterLevel(value),
+            Command::GetCharging => DeviceEvent::Charging(ChargingStatus::from(value)),
+            Command::GetAutomaticShutdown => {
+                DeviceEvent::AutomaticShutdownAfter(shutdown_after(spec, value))
+            }
+            Command::GetMute => DeviceEvent::Muted(spec.is_true(value)),
+            Command::GetMicConnected => DeviceEvent::MicConnected(spec.is_true(value)),
+            Command::GetPairingInfo => DeviceEvent::PairingInfo(value),
+            Command::GetProductColor => DeviceEvent::ProductColor(Color::from(value)),
+            Command::GetSideTone => DeviceEvent::SideToneOn(spec.is_true(value)),
+            Command::GetSideToneVolume => DeviceEvent::SideToneVolume(value),
+            Command::GetVoicePrompt => DeviceEvent::VoicePrompt(spec.is_true(value)),
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+/// The automatic shutdown is sent in whole steps, longer times are cut to the longest one.
+fn shutdown_steps(spec: &CommandDescriptor, after: Duration) -> u8 {
+    (after.as_secs() / spec.seconds_per_step()).min(u8::MAX as u64) as u8
+}
+
+fn shutdown_after(spec: &CommandDescriptor, steps: u8) -> Duration {
+    Duration::from_secs(steps as u64 * spec.seconds_per_step())
+}
+
+impl Device for GenericDevice {
+    fn get_charging_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetCharging)
+    }
+
+    fn get_battery_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetBattery)
+    }
+
+    fn set_automatic_shut_down_packet(&self, shutdown_after: Duration) -> Option<Vec<u8>> {
+        self.encode(Command::SetAutomaticShutdown(shutdown_after))
+    }
+
+    fn get_automatic_shut_down_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetAutomaticShutdown)
+    }
+
+    fn get_mute_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetMute)
+    }
+
+    fn set_mute_packet(&self, mute: bool) -> Option<Vec<u8>> {
+        self.encode(Command::SetMute(mute))
+    }
+
+    fn get_mic_connected_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetMicConnected)
+    }
+
+    fn get_pairing_info_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetPairingInfo)
+    }
+
+    fn get_product_color_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetProductColor)
+    }
+
+    fn get_side_tone_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetSideTone)
+    }
+
+    fn set_side_tone_packet(&self, side_tone_on: bool) -> Option<Vec<u8>> {
+        self.encode(Command::SetSideTone(side_tone_on))
+    }
+
+    fn get_side_tone_volume_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetSideToneVolume)
+    }
+
+    fn set_side_tone_volume_packet(&self, volume: u8) -> Option<Vec<u8>> {
+        self.encode(Command::SetSideToneVolume(volume))
+    }
+
+    fn get_voice_prompt_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetVoicePrompt)
+    }
+
+    fn set_voice_prompt_packet(&self, enable: bool) -> Option<Vec<u8>> {
+        self.encode(Command::SetVoicePrompt(enable))
+    }
+
+    fn get_wireless_connected_status_packet(&self) -> Option<Vec<u8>> {
+        self.encode(Command::GetWirelessConnected)
+    }
+
+    fn get_event_from_device_response(&self, response: &[u8]) -> Option<DeviceEvent> {
+        self.decode(response)
+    }
+
+    fn get_device_state(&self) -> &DeviceState {
+        &self.state
+    }
+
+    fn side_tone_volume_range(&self) -> RangeInclusive<u8> {
+        self.descriptor
+            .commands
+            .set_side_tone_volume
+            .as_ref()
+            .map_or(0..=u8::MAX, CommandDescriptor::range)
+    }
+
+    fn get_device_state_mut(&mut self) -> &mut DeviceState {
+        &mut self.state
+    }
+
+    /// The automatic shutdown is read back in the steps the headset stored.
+    fn expected_event(&self, setting: Command) -> Option<DeviceEvent> {
+        let commands = &self.descriptor.commands;
+        let (Command::SetAutomaticShutdown(after), Some(set), Some(get)) = (
+            setting,
+            commands.get(setting),
+            commands.get(Command::GetAutomaticShutdown),
+        ) else {
+            return setting.expected_event();
+        };
+        let stored = shutdown_after(get, shutdown_steps(set, after));
+        Some(DeviceEvent::AutomaticShutdownAfter(stored))
+    }
+}
+
+#[test]
+fn test_built_in_descriptor_matches_cloud_ii_protocol() {
+    use crate::devices::descriptor::built_in_descriptors;
+    use crate::devices::ChargingStatus;
+
+    let descriptor = built_in_descriptors()
+        .into_iter()
+        .find(|descriptor| descriptor.name == "HyperX Cloud II Wireless")
+        .unwrap();
+    // The command ID and value written at offset 3.
+    let commands = [
+        (Command::GetWirelessConnected, 1, 0),
+        (Command::GetBattery, 2, 0),
+        (Command::GetCharging, 3, 0),
+        (Command::GetAutomaticShutdown, 7, 0),
+        (
+            Command::SetAutomaticShutdown(Duration::from_secs(20 * 60)),
+            34,
+            20,
+        ),
+        (Command::GetMute, 5, 0),
+        (Command::SetMute(true), 32, 1),
+        (Command::GetMicConnected, 8, 0),
+        (Command::GetPairingInfo, 9, 0),
+        (Command::GetSideTone, 6, 0),
+        (Command::SetSideTone(true), 33, 1),
+        (Command::GetSideToneVolume, 11, 0),
+        (Command::SetSideToneVolume(12), 35, 12),
+    ];
+    let state = DeviceState::without_transport(0x018B, 0x03F0);
+    let device = GenericDevice::new(descriptor.clone(), state).unwrap();
+    for (command, id, value) in commands {
+        let mut packet = vec![0; 20];
+        (packet[0], packet[1], packet[2]) = (0x06, 0xff, 0xbb);
+        (packet[3], packet[4]) = (id, value);
+        assert_eq!(device.encode(command), Some(packet), "{command:?}");
+    }
+    // Broken on the Cloud II Wireless
+    assert_eq!(device.encode(Command::GetVoicePrompt), None);
+    for (response, event) in [
+        (
+            [0x06, 0xff, 0xbb, 1, 4, 0, 0, 0],
+            DeviceEvent::WirelessConnected(true),
+        ),
+        (
+            [0x06, 0xff, 0xbb, 2, 0, 0, 0, 42],
+            DeviceEvent::BatterLevel(42),
+        ),
+        (
+            [0x06, 0xff, 0xbb, 3, 2, 0, 0, 0],
+            DeviceEvent::Charging(ChargingStatus::FullyCharged),
+        ),
+        (
+            [0x06, 0xff, 0xbb, 7, 30, 0, 0, 0],
+            DeviceEvent::AutomaticShutdownAfter(Duration::from_secs(30 * 60)),
+        ),
+        ([0x06, 0xff, 0xbb, 5, 1, 0, 0, 0], DeviceEvent::Muted(true)),
+        (
+            [0x06, 0xff, 0xbb, 8, 0, 0, 0, 0],
+            DeviceEvent::MicConnected(false),
+        ),
+        (
+            [0x06, 0xff, 0xbb, 9, 3, 0, 0, 0],
+            DeviceEvent::PairingInfo(3),
+        ),
+        (
+            [0x06, 0xff, 0xbb, 6, 1, 0, 0, 0],
+            DeviceEvent::SideToneOn(true),
+        ),
+        (
+            [0x06, 0xff, 0xbb, 11, 7, 0, 0, 0],
+            DeviceEvent::SideToneVolume(7),
+        ),
+    ] {
+        assert_eq!(device.decode(&response), Some(event));
+    }
+    assert_eq!(device.decode(&[0x06, 0xff, 0xbb, 2]), None);
+    assert_eq!(device.decode(&[0x00, 0x00, 0x00, 2, 0, 0, 0, 50]), None);
+    // What dongles with the HyperX vendor ID answer with isn't known.
+    let state = DeviceState::without_transport(0x018B, 0x0696);
+    assert!(GenericDevice::new(descriptor, state).is_err());
+}
+
+#[test]
+fn test_automatic_shutdown_is_read_back_in_steps() {
+    use crate::devices::descriptor::DeviceDescriptor;
+
+    let descriptor = include_str!("descriptors/cloud_ii_wireless.toml")
+        .replace("seconds_per_step = 60", "seconds_per_step = 30");
+    let descriptor = DeviceDescriptor::from_toml(&descriptor).unwrap();
+    let state = DeviceState::without_transport(0x018B, 0x03F0);
+    let device = GenericDevice::new(descriptor, state).unwrap();
+    for (after, expected) in [(45, 30), (600, 600), (3 * 60 * 60, 255 * 30)] {
+        assert_eq!(
+            device.expected_event(Command::SetAutomaticShutdown(Duration::from_secs(after))),
+            Some(DeviceEvent::AutomaticShutdownAfter(Duration::from_secs(
+                expected
+            ))),
+            "{after} s"
+        );
+    }
+    assert_eq!(
+        device.expected_event(Command::SetMute(true)),
+        Some(DeviceEvent::Muted(true))
+    );
+}
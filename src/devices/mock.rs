@@ -0,0 +1,296 @@
+use crate::devices::descriptor::built_in_descriptors;
+use crate::devices::transport::Transport;
+use crate::devices::{
+    connect_with_descriptors, ChargingStatus, Device, DeviceError, DeviceEvent, DeviceState,
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+const PRODUCT_ID: u16 = 0x018B;
+const PRODUCT_NAME: &str = "HyperX Cloud II Wireless";
+const VENDOR_ID: u16 = 0x03F0;
+
+// The packets are written out byte by byte instead of reusing the code building them,
+// so tests notice when the device gets them wrong.
+const REQUEST_HEADER: [u8; 3] = [0x06, 0xff, 0xbb];
+const RESPONSE_HEADER: [u8; 3] = [0x06, 0xff, 0xbb];
+
+/// An in-memory Cloud II Wireless behind a dongle with the HP vendor ID, expecting 20 byte packets.
+/// Clones share the same headset, so a test can keep one to script scenarios while the device owns another.
+/// Reads never block, a timeout elapses instantly when there is nothing to answer.
+#[derive(Debug, Clone)]
+pub struct MockCloudIIWireless {
+    headset: Arc<Mutex<MockHeadset>>,
+}
+
+#[derive(Debug)]
+struct MockHeadset {
+    powered_on: bool,
+    battery_level: u8,
+    battery_drain: u8,
+    charging: ChargingStatus,
+    muted: bool,
+    mic_connected: bool,
+    automatic_shutdown_after: Duration,
+    pairing_info: u8,
+    side_tone_on: bool,
+    side_tone_volume: u8,
+    settings_locked: bool,
+    responses: VecDeque<[u8; 8]>,
+    written: Vec<Vec<u8>>,
+}
+
+impl MockCloudIIWireless {
+    pub fn new() -> Self {
+        MockCloudIIWireless {
+            headset: Arc::new(Mutex::new(MockHeadset {
+                powered_on: true,
+                battery_level: 100,
+                battery_drain: 0,
+                charging: ChargingStatus::NotCharging,
+                muted: false,
+                mic_connected: true,
+                automatic_shutdown_after: Duration::from_secs(10 * 60),
+                pairing_info: 0,
+                side_tone_on: false,
+                side_tone_volume: 0,
+                settings_locked: false,
+                responses: VecDeque::new(),
+                written: Vec::new(),
+            })),
+        }
+    }
+
+    /// Creates a device from the built-in Cloud II Wireless descriptor talking to this headset.
+    pub fn connect(&self) -> Result<Box<dyn Device>, DeviceError> {
+        let state = DeviceState::from_transport(Box::new(self.clone()), PRODUCT_ID, VENDOR_ID)?;
+        connect_with_descriptors(state, &built_in_descriptors())
+    }
+
+    pub fn set_battery_level(&self, level: u8) {
+        self.headset().battery_level = level;
+    }
+
+    /// Every battery query lowers the battery level by `step` percent after answering.
+    pub fn drain_battery(&self, step: u8) {
+        self.headset().battery_drain = step;
+    }
+
+    pub fn set_charging(&self, status: ChargingStatus) {
+        self.headset().charging = status;
+    }
+
+    /// The headset ignores every setter from now on, the values stay as they are.
+    pub fn lock_settings(&self) {
+        self.headset().settings_locked = true;
+    }
+
+    /// Changes the mute without an event, as if the event got lost.
+    pub fn set_muted(&self, muted: bool) {
+        self.headset().muted = muted;
+    }
+
+    /// The headset stops answering, as if it was switched off while the dongle stays plugged in.
+    pub fn power_off(&self) {
+        let mut headset = self.headset();
+        headset.powered_on = false;
+        headset.responses.clear();
+    }
+
+    pub fn power_on(&self) {
+        self.headset().powered_on = true;
+    }
+
+    pub fn unplug_mic(&self) {
+        self.headset().mic_connected = false;
+    }
+
+    pub fn plug_in_mic(&self) {
+        self.headset().mic_connected = true;
+    }
+
+    /// Queues a response the headset sends without being asked, e.g. after the mute button was pressed.
+    /// The internal state is updated as well so later queries agree with the event.
+    pub fn send_event(&self, event: DeviceEvent) {
+        let mut headset = self.headset();
+        match event {
+            DeviceEvent::BatterLevel(level) => headset.battery_level = level,
+            DeviceEvent::Muted(muted) => headset.muted = muted,
+            DeviceEvent::MicConnected(connected) => headset.mic_connected = connected,
+            DeviceEvent::Charging(status) => headset.charging = status,
+            DeviceEvent::AutomaticShutdownAfter(duration) => {
+                headset.automatic_shutdown_after = duration
+            }
+            DeviceEvent::PairingInfo(info) => headset.pairing_info = info,
+            DeviceEvent::SideToneOn(on) => headset.side_tone_on = on,
+            DeviceEvent::SideToneVolume(volume) => headset.side_tone_volume = volume,
+            DeviceEvent::WirelessConnected(connected) => headset.powered_on = connected,
+            DeviceEvent::ProductColor(_) | DeviceEvent::VoicePrompt(_) => (),
+        };
+        let response = headset.response(event);
+        headset.responses.push_back(response);
+    }
+
+    /// All packets written to the headset so far.
+    pub fn written_packets(&self) -> Vec<Vec<u8>> {
+        self.headset().written.clone()
+    }
+
+    fn headset(&self) -> MutexGuard<'_, MockHeadset> {
+        self.headset.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MockCloudIIWireless {
+    fn default() -> Self {
+        MockCloudIIWireless::new()
+    }
+}
+
+impl MockHeadset {
+    /// The command ID and value of a packet in the layout of this dongle.
+    fn parse_request(&self, packet: &[u8]) -> Option<(u8, u8)> {
+        let matches = packet.len() == 20
+            && packet.iter().enumerate().all(|(i, byte)| {
+                // The command ID and value follow the header at offsets 3 and 4.
+                i == 3 || i == 4 || *byte == REQUEST_HEADER.get(i).copied().unwrap_or(0)
+            });
+        matches.then(|| (packet[3], packet[4]))
+    }
+
+    /// The response the dongle sends for `event`.
+    fn response(&self, event: DeviceEvent) -> [u8; 8] {
+        let (id, value, level) = match event {
+            DeviceEvent::WirelessConnected(connected) => (1, connected as u8, 0),
+            DeviceEvent::BatterLevel(level) => (2, 0, level),
+            DeviceEvent::Charging(status) => (3, u8::from(status), 0),
+            DeviceEvent::Muted(muted) => (5, muted as u8, 0),
+            DeviceEvent::SideToneOn(on) => (6, on as u8, 0),
+            DeviceEvent::AutomaticShutdownAfter(after) => (7, (after.as_secs() / 60) as u8, 0),
+            DeviceEvent::MicConnected(connected) => (8, connected as u8, 0),
+            DeviceEvent::PairingInfo(info) => (9, info, 0),
+            DeviceEvent::SideToneVolume(volume) => (11, volume, 0),
+            DeviceEvent::VoicePrompt(on) => return [0x21, 0xbb, 9, on as u8, 0, 0, 0, 0],
+            DeviceEvent::ProductColor(color) => {
+                return [0x21, 0xbb, 14, u8::from(color), 0, 0, 0, 0]
+            }
+        };
+        let [a, b, c] = RESPONSE_HEADER;
+        [a, b, c, id, value, 0, 0, level]
+    }
+
+    fn handle(&mut self, packet: &[u8]) {
+        // Like the real headset, the mock doesn't answer the secondary packets.
+        let Some((id, value)) = self.parse_request(packet).filter(|_| self.powered_on) else {
+            return;
+        };
+        if self.settings_locked && id >= 32 {
+            return;
+        }
+        let reply = match id {
+            1 => DeviceEvent::WirelessConnected(true),
+            2 => {
+                let level = self.battery_level;
+                self.battery_level = level.saturating_sub(self.battery_drain);
+                DeviceEvent::BatterLevel(level)
+            }
+            3 => DeviceEvent::Charging(self.charging),
+            5 => DeviceEvent::Muted(self.muted),
+            6 => DeviceEvent::SideToneOn(self.side_tone_on),
+            7 => DeviceEvent::AutomaticShutdownAfter(self.automatic_shutdown_after),
+            8 => DeviceEvent::MicConnected(self.mic_connected),
+            9 => DeviceEvent::PairingInfo(self.pairing_info),
+            11 => DeviceEvent::SideToneVolume(self.side_tone_volume),
+            // Setters are not acknowledged, the new value can be queried afterwards.
+            32 => {
+                self.muted = value == 1;
+                return;
+            }
+            33 => {
+                self.side_tone_on = value == 1;
+                return;
+            }
+            34 => {
+                self.automatic_shutdown_after = Duration::from_secs(value as u64 * 60);
+                return;
+            }
+            35 => {
+                self.side_tone_volume = value;
+                return;
+            }
+            _ => return,
+        };
+        let response = self.response(reply);
+        self.responses.push_back(response);
+    }
+}
+
+impl Transport for MockCloudIIWireless {
+    fn write(&self, data: &[u8]) -> Result<usize, DeviceError> {
+        let mut headset = self.headset();
+        headset.written.push(data.to_vec());
+        headset.handle(data);
+        Ok(data.len())
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, DeviceError> {
+        match self.headset().responses.pop_front() {
+            Some(response) => {
+                let len = response.len().min(buf.len());
+                buf[..len].copy_from_slice(&response[..len]);
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn get_product_string(&self) -> Result<Option<String>, DeviceError> {
+        Ok(Some(PRODUCT_NAME.to_string()))
+    }
+}
+
+#[test]
+fn test_mock_battery_draining() {
+    let headset = MockCloudIIWireless::new();
+    headset.set_battery_level(50);
+    headset.drain_battery(10);
+    let mut device = headset.connect().unwrap();
+    device.refresh_state().unwrap();
+    assert_eq!(device.get_device_state().battery_level, Some(50));
+    device.refresh_state().unwrap();
+    assert_eq!(device.get_device_state().battery_level, Some(40));
+}
+
+#[test]
+fn test_mock_power_off() {
+    let headset = MockCloudIIWireless::new();
+    let mut device = headset.connect().unwrap();
+    device.refresh_state().unwrap();
+    assert_eq!(device.get_device_state().connected, Some(true));
+    headset.power_off();
+    assert!(matches!(
+        device.refresh_state(),
+        Err(DeviceError::NoResponse())
+    ));
+}
+
+#[test]
+fn test_mock_mic_unplugged_and_unsolicited_events() {
+    let headset = MockCloudIIWireless::new();
+    headset.unplug_mic();
+    let mut device = headset.connect().unwrap();
+    device.refresh_state().unwrap();
+    assert_eq!(device.get_device_state().mic_connected, Some(false));
+    assert_eq!(device.get_device_state().muted, Some(false));
+
+    headset.send_event(DeviceEvent::Muted(true));
+    assert!(matches!(
+        device.wait_for_updates(Duration::from_secs(1)),
+        Some(DeviceEvent::Muted(true))
+    ));
+    device.refresh_state().unwrap();
+    assert_eq!(device.get_device_state().muted, Some(true));
+}
@@ -1,31 +1,156 @@
 pub mod cloud_ii_wireless_dts;
+pub mod descriptor;
+pub mod generic;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod recording;
+pub mod transport;
 
-use crate::devices::cloud_ii_wireless_dts::CloudIIWirelessDTS;
-use hidapi::{HidApi, HidDevice, HidError};
-use std::{fmt::Display, time::Duration};
+use crate::devices::descriptor::{load_descriptors, DeviceDescriptor};
+use crate::devices::generic::GenericDevice;
+use crate::devices::recording::{RecordingTransport, ReplayTransport};
+use crate::devices::transport::{NullTransport, Transport};
+use hidapi::{HidApi, HidError};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::CString,
+    fmt::Display,
+    ops::RangeInclusive,
+    path::Path,
+    time::{Duration, Instant},
+};
 use thistermination::TerminationFull;
 
-// Possible vendor IDs [HP]
-const VENDOR_IDS: [u16; 1] = [0x03F0];
-// Possible Cloud II Wireless product IDs
-const PRODUCT_IDS: [u16; 4] = [0x1718, 0x018B, 0x0D93, 0x0696];
+// Unrelated events received while waiting for a response, older ones are dropped first
+const MAX_PENDING_EVENTS: usize = 64;
 
 pub fn connect_compatible_device() -> Result<Box<dyn Device>, DeviceError> {
-    let state = DeviceState::new(&PRODUCT_IDS, &VENDOR_IDS)?;
+    connect_device(&first_compatible_device()?)
+}
+
+/// Like `connect_compatible_device`, but every packet exchanged with the device is logged to `path`.
+pub fn connect_compatible_device_recording(path: &Path) -> Result<Box<dyn Device>, DeviceError> {
+    connect_to_state(DeviceState::open(&first_compatible_device()?)?.record_to(path)?)
+}
+
+fn first_compatible_device() -> Result<DeviceInfo, DeviceError> {
+    list_compatible_devices()?
+        .into_iter()
+        .next()
+        .ok_or(DeviceError::NoDeviceFound())
+}
+
+/// Connects to a device that replays a session recorded with `connect_compatible_device_recording`.
+pub fn connect_replay(path: &Path) -> Result<Box<dyn Device>, DeviceError> {
+    connect_to_state(DeviceState::from_recording(path)?)
+}
+
+pub fn connect_device(info: &DeviceInfo) -> Result<Box<dyn Device>, DeviceError> {
+    connect_to_state(DeviceState::open(info)?)
+}
+
+/// Picks the device descriptor matching the product string of the opened device, see `load_descriptors`.
+pub fn connect_to_state(state: DeviceState) -> Result<Box<dyn Device>, DeviceError> {
+    connect_with_descriptors(state, &load_descriptors())
+}
+
+pub fn connect_with_descriptors(
+    state: DeviceState,
+    descriptors: &[DeviceDescriptor],
+) -> Result<Box<dyn Device>, DeviceError> {
     let name = state
-        .hid_device
+        .transport
         .get_product_string()?
         .ok_or(DeviceError::NoDeviceFound())?;
-    println!("Connecting to {}", name);
-    match name.as_str() {
-        "HyperX Cloud II Wireless" => Ok(Box::new(CloudIIWirelessDTS::new_from_state(state))),
-        _ => Err(DeviceError::NoDeviceFound()),
+    let descriptor = descriptors
+        .iter()
+        .find(|descriptor| descriptor.product_strings.contains(&name))
+        .ok_or(DeviceError::NoDeviceFound())?;
+    Ok(Box::new(GenericDevice::new(descriptor.clone(), state)?))
+}
+
+/// Lists every connected device supported by one of the device descriptors, one entry per dongle.
+pub fn list_compatible_devices() -> Result<Vec<DeviceInfo>, DeviceError> {
+    let descriptors = load_descriptors();
+    let hid_api = HidApi::new()?;
+    // A dongle exposes several HID interfaces, like `HidApi::open` only the first enumerated one is used.
+    let mut interfaces = HashMap::new();
+    let devices = hid_api
+        .device_list()
+        .filter(|info| {
+            descriptors
+                .iter()
+                .any(|descriptor| descriptor.supports(info.vendor_id(), info.product_id()))
+        })
+        .filter(|info| {
+            is_first_interface(
+                &mut interfaces,
+                (info.vendor_id(), info.product_id()),
+                info.interface_number(),
+            )
+        })
+        .map(|info| DeviceInfo {
+            path: info.path().to_string_lossy().into_owned(),
+            serial_number: info.serial_number().filter(|s| !s.is_empty()).map(str::to_string),
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            product_string: info.product_string().map(str::to_string),
+        })
+        .collect();
+    Ok(devices)
+}
+
+/// Whether `interface` is the interface seen first for the vendor and product ID in `ids`.
+/// Identical dongles share their interface numbers, so each of them is kept.
+fn is_first_interface(
+    interfaces: &mut HashMap<(u16, u16), i32>,
+    ids: (u16, u16),
+    interface: i32,
+) -> bool {
+    *interfaces.entry(ids).or_insert(interface) == interface
+}
+
+/// A compatible device found by `list_compatible_devices`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub serial_number: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub product_string: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Whether `selector` is the serial number, the hidapi path
+    /// or the vendor and product ID as `03f0:018b` of this device.
+    pub fn matches(&self, selector: &str) -> bool {
+        self.path == selector
+            || self.serial_number.as_deref() == Some(selector)
+            || selector
+                .eq_ignore_ascii_case(&format!("{:04x}:{:04x}", self.vendor_id, self.product_id))
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}] serial: {} path: {}",
+            self.product_string.as_deref().unwrap_or("Unknown"),
+            self.vendor_id,
+            self.product_id,
+            self.serial_number.as_deref().unwrap_or("Unknown"),
+            self.path,
+        )
     }
 }
 
-#[derive(Debug)]
+/// Serializes to the known values, unknown ones become `null`.
+#[derive(Debug, Serialize)]
 pub struct DeviceState {
-    pub hid_device: HidDevice,
+    #[serde(skip)]
+    pub transport: Box<dyn Transport>,
     pub product_id: u16,
     pub vendor_id: u16,
     pub device_name: Option<String>,
@@ -33,6 +158,7 @@ pub struct DeviceState {
     pub charging: Option<ChargingStatus>,
     pub muted: Option<bool>,
     pub mic_connected: Option<bool>,
+    #[serde(rename = "automatic_shutdown_minutes", serialize_with = "minutes::serialize_option")]
     pub automatic_shutdown_after: Option<Duration>,
     pub pairing_info: Option<u8>,
     pub product_color: Option<Color>,
@@ -40,6 +166,43 @@ pub struct DeviceState {
     pub side_tone_volume: Option<u8>,
     pub voice_prompt_on: Option<bool>,
     pub connected: Option<bool>,
+    /// Estimated from the battery history while discharging, see `BatteryHistory::update_estimates`.
+    #[serde(
+        rename = "time_remaining_minutes",
+        serialize_with = "minutes::serialize_option"
+    )]
+    pub time_remaining: Option<Duration>,
+    /// Estimated from the battery history while charging.
+    #[serde(
+        rename = "time_to_full_minutes",
+        serialize_with = "minutes::serialize_option"
+    )]
+    pub time_to_full: Option<Duration>,
+    #[serde(skip)]
+    pub pending_events: VecDeque<DeviceEvent>,
+    #[serde(skip)]
+    pub retry_policy: RetryPolicy,
+}
+
+/// How `Device::query` waits for the response to a command.
+#[derive(Debug, Copy, Clone)]
+pub struct RetryPolicy {
+    /// How long to wait for the response to a single attempt.
+    pub timeout: Duration,
+    /// How often the command is sent before giving up.
+    pub attempts: u32,
+    /// Pause before the first retry, doubled for every further retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_secs(1),
+            attempts: 2,
+            backoff: Duration::from_millis(100),
+        }
+    }
 }
 
 impl Display for DeviceState {
@@ -56,7 +219,9 @@ Product color:            {}
 Side tone on:             {}
 Side tone volume:         {}
 Voice prompt on:          {}
-Connected:                {}",
+Connected:                {}
+Time remaining:           {}
+Time to full:             {}",
            self.device_name.clone().unwrap_or("Unknown".to_string()),
            self.battery_level
                .map_or(unknown.clone(), |l| format!("{l}%")),
@@ -75,6 +240,8 @@ Connected:                {}",
            self.voice_prompt_on
                .map_or(unknown.clone(), |v| v.to_string()),
            self.connected.map_or(unknown.clone(), |c| c.to_string()),
+           self.time_remaining.map_or(unknown.clone(), hours_and_minutes),
+           self.time_to_full.map_or(unknown.clone(), hours_and_minutes),
         )
     }
 }
@@ -94,10 +261,37 @@ impl DeviceState {
                 }
             })
             .ok_or(DeviceError::NoDeviceFound())?;
-        let hid_device = hid_device?;
-        let device_name = hid_device.get_product_string()?;
-        Ok(DeviceState {
-            hid_device,
+        DeviceState::from_transport(Box::new(hid_device?), product_id, vendor_id)
+    }
+
+    pub fn open(info: &DeviceInfo) -> Result<Self, DeviceError> {
+        let path = CString::new(info.path.as_str()).map_err(|_| DeviceError::NoDeviceFound())?;
+        let hid_device = HidApi::new()?.open_path(&path)?;
+        DeviceState::from_transport(Box::new(hid_device), info.product_id, info.vendor_id)
+    }
+
+    pub fn from_transport(
+        transport: Box<dyn Transport>,
+        product_id: u16,
+        vendor_id: u16,
+    ) -> Result<Self, DeviceError> {
+        let device_name = transport.get_product_string()?;
+        Ok(DeviceState::with_name(transport, product_id, vendor_id, device_name))
+    }
+
+    /// A state with all values unknown that can't talk to a headset, see `NullTransport`.
+    pub fn without_transport(product_id: u16, vendor_id: u16) -> Self {
+        DeviceState::with_name(Box::new(NullTransport), product_id, vendor_id, None)
+    }
+
+    fn with_name(
+        transport: Box<dyn Transport>,
+        product_id: u16,
+        vendor_id: u16,
+        device_name: Option<String>,
+    ) -> Self {
+        DeviceState {
+            transport,
             product_id,
             vendor_id,
             device_name,
@@ -112,9 +306,29 @@ impl DeviceState {
             side_tone_volume: None,
             voice_prompt_on: None,
             connected: None,
+            time_remaining: None,
+            time_to_full: None,
+            pending_events: VecDeque::new(),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Logs all packets exchanged with the device to `path`, see `RecordingTransport`.
+    pub fn record_to(self, path: &Path) -> Result<Self, DeviceError> {
+        let transport =
+            RecordingTransport::new(self.transport, path, self.vendor_id, self.product_id)?;
+        Ok(DeviceState {
+            transport: Box::new(transport),
+            ..self
         })
     }
 
+    pub fn from_recording(path: &Path) -> Result<Self, DeviceError> {
+        let transport = ReplayTransport::open(path)?;
+        let (product_id, vendor_id) = (transport.product_id, transport.vendor_id);
+        DeviceState::from_transport(Box::new(transport), product_id, vendor_id)
+    }
+
     pub fn to_string_no_padding(&self) -> String {
         let unknown = "Unknown".to_string();
         format!("Battery level: {}
@@ -127,7 +341,9 @@ Product color: {}
 Side tone on: {}
 Side tone volume: {}
 Voice prompt on: {}
-Connected: {}",
+Connected: {}
+Time remaining: {}
+Time to full: {}",
             self.battery_level
                 .map_or(unknown.clone(), |l| format!("{l}%")),
             self.charging.map_or(unknown.clone(), |c| c.to_string()),
@@ -145,10 +361,12 @@ Connected: {}",
             self.voice_prompt_on
                 .map_or(unknown.clone(), |v| v.to_string()),
             self.connected.map_or(unknown.clone(), |c| c.to_string()),
+            self.time_remaining.map_or(unknown.clone(), hours_and_minutes),
+            self.time_to_full.map_or(unknown.clone(), hours_and_minutes),
         )
     }
 
-    fn update_self_with_event(&mut self, event: &DeviceEvent) {
+    pub(crate) fn update_self_with_event(&mut self, event: &DeviceEvent) {
         match event {
             DeviceEvent::BatterLevel(level) => self.battery_level = Some(*level),
             DeviceEvent::Charging(status) => self.charging = Some(*status),
@@ -166,11 +384,42 @@ Connected: {}",
         };
     }
 
+    /// The known values as the events that would set them.
+    pub fn events(&self) -> Vec<DeviceEvent> {
+        [
+            self.connected.map(DeviceEvent::WirelessConnected),
+            self.battery_level.map(DeviceEvent::BatterLevel),
+            self.charging.map(DeviceEvent::Charging),
+            self.muted.map(DeviceEvent::Muted),
+            self.mic_connected.map(DeviceEvent::MicConnected),
+            self.automatic_shutdown_after.map(DeviceEvent::AutomaticShutdownAfter),
+            self.pairing_info.map(DeviceEvent::PairingInfo),
+            self.product_color.map(DeviceEvent::ProductColor),
+            self.side_tone_on.map(DeviceEvent::SideToneOn),
+            self.side_tone_volume.map(DeviceEvent::SideToneVolume),
+            self.voice_prompt_on.map(DeviceEvent::VoicePrompt),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
     pub fn clear_state(&mut self) {
         self.charging = None;
         self.battery_level = None;
         self.muted = None;
         self.mic_connected = None;
+        self.time_remaining = None;
+        self.time_to_full = None;
+    }
+}
+
+/// `3 h 25 min`, or `25 min` below an hour.
+pub fn hours_and_minutes(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    match minutes / 60 {
+        0 => format!("{minutes} min"),
+        hours => format!("{hours} h {} min", minutes % 60),
     }
 }
 
@@ -186,14 +435,37 @@ pub enum DeviceError {
     NoResponse(),
     #[termination(msg("Unknown response: {0:?} with length: {1:?}"))]
     UnknownResponse([u8; 8], usize),
+    #[termination(msg("{0:?}"))]
+    IoError(#[from] std::io::Error),
+    #[termination(msg("Invalid recording: {0}"))]
+    InvalidRecording(String),
+    #[termination(msg("The replay diverged from the recording: {0}"))]
+    ReplayDiverged(String),
+    #[termination(msg("{0:?} is not supported by this device."))]
+    NotSupported(Command),
+    #[termination(msg("Invalid device descriptor: {0}"))]
+    InvalidDescriptor(String),
+    #[termination(msg("Invalid config file: {0}"))]
+    InvalidConfig(String),
+    #[termination(msg("{0}"))]
+    Daemon(String),
+    #[termination(msg("The headset didn't apply the setting, expected \"{expected}\" but read \"{actual}\"."))]
+    SettingNotApplied {
+        expected: DeviceEvent,
+        actual: DeviceEvent,
+    },
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Serializes as `{"event": "muted", "value": true}`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "value", rename_all = "snake_case")]
 pub enum DeviceEvent {
+    #[serde(rename = "battery_level")]
     BatterLevel(u8),
     Muted(bool),
     MicConnected(bool),
     Charging(ChargingStatus),
+    #[serde(rename = "automatic_shutdown_minutes", with = "minutes")]
     AutomaticShutdownAfter(Duration),
     PairingInfo(u8),
     ProductColor(Color),
@@ -203,7 +475,174 @@ pub enum DeviceEvent {
     WirelessConnected(bool),
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Display for DeviceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceEvent::BatterLevel(level) => write!(f, "Battery level: {level}%"),
+            DeviceEvent::Muted(muted) => write!(f, "Muted: {muted}"),
+            DeviceEvent::MicConnected(connected) => write!(f, "Mic connected: {connected}"),
+            DeviceEvent::Charging(status) => write!(f, "Charging status: {status}"),
+            DeviceEvent::AutomaticShutdownAfter(after) => {
+                write!(f, "Automatic shutdown after: {} min", after.as_secs() / 60)
+            }
+            DeviceEvent::PairingInfo(info) => write!(f, "Pairing info: {info}"),
+            DeviceEvent::ProductColor(color) => write!(f, "Product color: {color}"),
+            DeviceEvent::SideToneOn(on) => write!(f, "Side tone on: {on}"),
+            DeviceEvent::SideToneVolume(volume) => write!(f, "Side tone volume: {volume}"),
+            DeviceEvent::VoicePrompt(on) => write!(f, "Voice prompt on: {on}"),
+            DeviceEvent::WirelessConnected(connected) => write!(f, "Connected: {connected}"),
+        }
+    }
+}
+
+/// A request that can be sent to a device, independent of how the device encodes it.
+/// Serializes as `"get_battery"` or `{"set_mute": true}`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    GetWirelessConnected,
+    GetBattery,
+    GetCharging,
+    GetAutomaticShutdown,
+    #[serde(rename = "set_automatic_shutdown_minutes", with = "minutes")]
+    SetAutomaticShutdown(Duration),
+    GetMute,
+    SetMute(bool),
+    GetMicConnected,
+    GetPairingInfo,
+    GetProductColor,
+    GetSideTone,
+    SetSideTone(bool),
+    GetSideToneVolume,
+    SetSideToneVolume(u8),
+    GetVoicePrompt,
+    SetVoicePrompt(bool),
+}
+
+impl Command {
+    /// Every command reading a value from the device, in the order `refresh_state` queries them.
+    pub const GETTERS: [Command; 11] = [
+        Command::GetWirelessConnected,
+        Command::GetCharging,
+        Command::GetBattery,
+        Command::GetAutomaticShutdown,
+        Command::GetMute,
+        Command::GetMicConnected,
+        Command::GetPairingInfo,
+        Command::GetProductColor,
+        Command::GetSideTone,
+        Command::GetSideToneVolume,
+        Command::GetVoicePrompt,
+    ];
+
+    /// Whether `event` is the response to this command. Setters are never answered.
+    pub fn is_answered_by(&self, event: &DeviceEvent) -> bool {
+        matches!(
+            (self, event),
+            (Command::GetWirelessConnected, DeviceEvent::WirelessConnected(_))
+                | (Command::GetBattery, DeviceEvent::BatterLevel(_))
+                | (Command::GetCharging, DeviceEvent::Charging(_))
+                | (Command::GetAutomaticShutdown, DeviceEvent::AutomaticShutdownAfter(_))
+                | (Command::GetMute, DeviceEvent::Muted(_))
+                | (Command::GetMicConnected, DeviceEvent::MicConnected(_))
+                | (Command::GetPairingInfo, DeviceEvent::PairingInfo(_))
+                | (Command::GetProductColor, DeviceEvent::ProductColor(_))
+                | (Command::GetSideTone, DeviceEvent::SideToneOn(_))
+                | (Command::GetSideToneVolume, DeviceEvent::SideToneVolume(_))
+                | (Command::GetVoicePrompt, DeviceEvent::VoicePrompt(_))
+        )
+    }
+
+    /// The command reading back the value changed by this setter, `None` for getters.
+    pub fn getter(&self) -> Option<Command> {
+        match self {
+            Command::SetAutomaticShutdown(_) => Some(Command::GetAutomaticShutdown),
+            Command::SetMute(_) => Some(Command::GetMute),
+            Command::SetSideTone(_) => Some(Command::GetSideTone),
+            Command::SetSideToneVolume(_) => Some(Command::GetSideToneVolume),
+            Command::SetVoicePrompt(_) => Some(Command::GetVoicePrompt),
+            _ => None,
+        }
+    }
+
+    /// The event the getter answers with once this setter was applied, `None` for getters.
+    pub fn expected_event(&self) -> Option<DeviceEvent> {
+        match *self {
+            Command::SetAutomaticShutdown(after) => {
+                Some(DeviceEvent::AutomaticShutdownAfter(after))
+            }
+            Command::SetMute(mute) => Some(DeviceEvent::Muted(mute)),
+            Command::SetSideTone(on) => Some(DeviceEvent::SideToneOn(on)),
+            Command::SetSideToneVolume(volume) => Some(DeviceEvent::SideToneVolume(volume)),
+            Command::SetVoicePrompt(enable) => Some(DeviceEvent::VoicePrompt(enable)),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a feature can be read from and changed on the device.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Access {
+    pub fn is_supported(&self) -> bool {
+        self.readable || self.writable
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.readable && !self.writable
+    }
+}
+
+/// The features supported by a device, see `Device::capabilities`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub battery: Access,
+    pub charging: Access,
+    pub mute: Access,
+    pub side_tone: Access,
+    pub side_tone_volume: Access,
+    pub side_tone_volume_range: RangeInclusive<u8>,
+    pub automatic_shutdown: Access,
+    pub voice_prompt: Access,
+    pub product_color: Access,
+}
+
+impl Capabilities {
+    /// `supports` tells whether a command is supported, regardless of its value.
+    pub fn new(
+        supports: impl Fn(Command) -> bool,
+        side_tone_volume_range: RangeInclusive<u8>,
+    ) -> Self {
+        let access = |get: Command, set: Option<Command>| Access {
+            readable: supports(get),
+            writable: set.is_some_and(&supports),
+        };
+        Capabilities {
+            battery: access(Command::GetBattery, None),
+            charging: access(Command::GetCharging, None),
+            mute: access(Command::GetMute, Some(Command::SetMute(true))),
+            side_tone: access(Command::GetSideTone, Some(Command::SetSideTone(true))),
+            side_tone_volume: access(
+                Command::GetSideToneVolume,
+                Some(Command::SetSideToneVolume(0)),
+            ),
+            side_tone_volume_range,
+            automatic_shutdown: access(
+                Command::GetAutomaticShutdown,
+                Some(Command::SetAutomaticShutdown(Duration::ZERO)),
+            ),
+            voice_prompt: access(Command::GetVoicePrompt, Some(Command::SetVoicePrompt(true))),
+            product_color: access(Command::GetProductColor, None),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Color {
     Red,
     UnknownColor(u8),
@@ -231,7 +670,17 @@ impl From<u8> for Color {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl From<Color> for u8 {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::Red => 0,
+            Color::UnknownColor(color) => color,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ChargingStatus {
     NotCharging,
     Charging,
@@ -265,6 +714,48 @@ impl From<u8> for ChargingStatus {
     }
 }
 
+impl From<ChargingStatus> for u8 {
+    fn from(status: ChargingStatus) -> Self {
+        match status {
+            ChargingStatus::NotCharging => 0,
+            ChargingStatus::Charging => 1,
+            ChargingStatus::FullyCharged => 2,
+            ChargingStatus::ChargeError => 3,
+        }
+    }
+}
+
+/// (De)serializes durations as whole minutes, the resolution the headsets use.
+pub(crate) mod minutes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs() / 60)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(deserializer)? * 60))
+    }
+
+    pub fn serialize_option<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize_option<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        let minutes = Option::<u64>::deserialize(deserializer)?;
+        Ok(minutes.map(|minutes| Duration::from_secs(minutes * 60)))
+    }
+}
+
 pub trait Device {
     fn get_charging_packet(&self) -> Option<Vec<u8>>;
     fn get_battery_packet(&self) -> Option<Vec<u8>>;
@@ -285,44 +776,137 @@ pub trait Device {
     fn get_event_from_device_response(&self, response: &[u8]) -> Option<DeviceEvent>;
     fn get_device_state(&self) -> &DeviceState;
     fn get_device_state_mut(&mut self) -> &mut DeviceState;
-    fn wait_for_updates(&mut self, duration: Duration) -> Option<DeviceEvent> {
+
+    fn side_tone_volume_range(&self) -> RangeInclusive<u8> {
+        0..=u8::MAX
+    }
+
+    /// What the getter reads back once `setting` was applied, see `Command::expected_event`.
+    /// Devices override it for values they can't store exactly.
+    fn expected_event(&self, setting: Command) -> Option<DeviceEvent> {
+        setting.expected_event()
+    }
+
+    /// Lists what the device supports, derived from the packets it can build.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::new(
+            |command| self.get_command_packet(command).is_some(),
+            self.side_tone_volume_range(),
+        )
+    }
+
+    fn get_command_packet(&self, command: Command) -> Option<Vec<u8>> {
+        match command {
+            Command::GetWirelessConnected => self.get_wireless_connected_status_packet(),
+            Command::GetBattery => self.get_battery_packet(),
+            Command::GetCharging => self.get_charging_packet(),
+            Command::GetAutomaticShutdown => self.get_automatic_shut_down_packet(),
+            Command::SetAutomaticShutdown(after) => self.set_automatic_shut_down_packet(after),
+            Command::GetMute => self.get_mute_packet(),
+            Command::SetMute(mute) => self.set_mute_packet(mute),
+            Command::GetMicConnected => self.get_mic_connected_packet(),
+            Command::GetPairingInfo => self.get_pairing_info_packet(),
+            Command::GetProductColor => self.get_product_color_packet(),
+            Command::GetSideTone => self.get_side_tone_packet(),
+            Command::SetSideTone(on) => self.set_side_tone_packet(on),
+            Command::GetSideToneVolume => self.get_side_tone_volume_packet(),
+            Command::SetSideToneVolume(volume) => self.set_side_tone_volume_packet(volume),
+            Command::GetVoicePrompt => self.get_voice_prompt_packet(),
+            Command::SetVoicePrompt(enable) => self.set_voice_prompt_packet(enable),
+        }
+    }
+
+    /// Returns the next queued or unsolicited event, `Ok(None)` if nothing arrived within `timeout`.
+    fn next_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DeviceError> {
+        if let Some(event) = self.get_device_state_mut().pending_events.pop_front() {
+            return Ok(Some(event));
+        }
+
         let mut buf = [0u8; 8];
         let res = self
             .get_device_state()
-            .hid_device
-            .read_timeout(&mut buf[..], duration.as_millis() as i32)
-            .ok()?;
+            .transport
+            .read_timeout(&mut buf[..], timeout)?;
 
         if res == 0 {
-            return None;
+            return Ok(None);
         }
 
-        self.get_event_from_device_response(&buf[0..res])
+        Ok(self.get_event_from_device_response(&buf[0..res]))
     }
 
-    fn refresh_state(&mut self) -> Result<(), DeviceError> {
-        let packets = vec![
-            self.get_wireless_connected_status_packet(),
-            self.get_charging_packet(),
-            self.get_battery_packet(),
-            self.get_automatic_shut_down_packet(),
-            self.get_mute_packet(),
-            self.get_mic_connected_packet(),
-            self.get_pairing_info_packet(),
-            self.get_product_color_packet(),
-            self.get_side_tone_packet(),
-            self.get_side_tone_volume_packet(),
-            self.get_voice_prompt_packet(),
-        ];
+    fn wait_for_updates(&mut self, duration: Duration) -> Option<DeviceEvent> {
+        self.next_event(duration).ok().flatten()
+    }
 
+    /// Sends `command` without waiting for a response.
+    fn send(&mut self, command: Command) -> Result<(), DeviceError> {
+        let packet = self
+            .get_command_packet(command)
+            .ok_or(DeviceError::NotSupported(command))?;
+        self.get_device_state().transport.write(&packet)?;
+        Ok(())
+    }
+
+    /// Sends the setter `setting` and reads the value back, which is applied to the state.
+    /// Fails with `SettingNotApplied` if the headset didn't take the value.
+    /// Returns `None` if the headset can't read it back, getters are only queried.
+    fn apply(&mut self, setting: Command) -> Result<Option<DeviceEvent>, DeviceError> {
+        let (Some(getter), Some(expected)) = (setting.getter(), self.expected_event(setting))
+        else {
+            return self.query(setting).map(Some);
+        };
+        self.send(setting)?;
+        if self.get_command_packet(getter).is_none() {
+            return Ok(None);
+        }
+        let actual = self.query(getter)?;
+        self.get_device_state_mut().update_self_with_event(&actual);
+        if actual != expected {
+            return Err(DeviceError::SettingNotApplied { expected, actual });
+        }
+        Ok(Some(actual))
+    }
+
+    /// Sends `command` and waits for the event answering it, retrying according to the `retry_policy`.
+    /// Unrelated events read in the meantime are applied to the state and returned by `wait_for_updates` later.
+    fn query(&mut self, command: Command) -> Result<DeviceEvent, DeviceError> {
+        let packet = self
+            .get_command_packet(command)
+            .ok_or(DeviceError::NotSupported(command))?;
+        let policy = self.get_device_state().retry_policy;
+        let mut backoff = policy.backoff;
+        for attempt in 0..policy.attempts.max(1) {
+            if attempt > 0 {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            self.get_device_state().transport.write(&packet)?;
+            if let Received::Answer(event) = receive_answer(self, command, policy.timeout)? {
+                return Ok(event);
+            }
+        }
+        Err(DeviceError::NoResponse())
+    }
+
+    /// Queries every getter once, without retries. Any response counts as the headset being reachable.
+    fn refresh_state(&mut self) -> Result<(), DeviceError> {
+        let timeout = self.get_device_state().retry_policy.timeout;
         let mut responded = false;
-        for packet in packets.into_iter().flatten() {
-            self.get_device_state().hid_device.write(&packet)?;
-            if let Some(event) = self.wait_for_updates(Duration::from_secs(1)) {
-                self.get_device_state_mut().update_self_with_event(&event);
-                responded = true;
+        for command in Command::GETTERS {
+            let Some(packet) = self.get_command_packet(command) else {
+                continue;
+            };
+            self.get_device_state().transport.write(&packet)?;
+            match receive_answer(self, command, timeout)? {
+                Received::Answer(event) => {
+                    self.get_device_state_mut().update_self_with_event(&event);
+                    responded = true;
+                }
+                Received::Other => responded = true,
+                Received::Nothing => (),
             }
-            if !self.get_device_state().connected.map_or(true, |c| c) {
+            if !self.get_device_state().connected.is_none_or(|c| c) {
                 break;
             }
         }
@@ -334,3 +918,317 @@ pub trait Device {
         }
     }
 }
+
+/// A headset opened directly or held open by the tray, see `daemon::client::RemoteDevice`.
+/// Unlike a `Device` it doesn't build packets, so it can stand for a headset behind the tray.
+pub trait Headset {
+    fn capabilities(&self) -> Capabilities;
+    /// Whether `command` is supported, regardless of its value.
+    fn supports(&self, command: Command) -> bool;
+    fn get_device_state(&self) -> &DeviceState;
+    fn get_device_state_mut(&mut self) -> &mut DeviceState;
+    fn refresh_state(&mut self) -> Result<(), DeviceError>;
+    fn query(&mut self, command: Command) -> Result<DeviceEvent, DeviceError>;
+    fn apply(&mut self, setting: Command) -> Result<Option<DeviceEvent>, DeviceError>;
+    fn next_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DeviceError>;
+    /// Flips the mute going by the headset, returns the new value once the headset confirmed it.
+    fn toggle_mute(&mut self) -> Result<bool, DeviceError>;
+}
+
+impl<D: Device + ?Sized> Headset for D {
+    fn capabilities(&self) -> Capabilities {
+        Device::capabilities(self)
+    }
+
+    fn supports(&self, command: Command) -> bool {
+        self.get_command_packet(command).is_some()
+    }
+
+    fn get_device_state(&self) -> &DeviceState {
+        Device::get_device_state(self)
+    }
+
+    fn get_device_state_mut(&mut self) -> &mut DeviceState {
+        Device::get_device_state_mut(self)
+    }
+
+    fn refresh_state(&mut self) -> Result<(), DeviceError> {
+        Device::refresh_state(self)
+    }
+
+    fn query(&mut self, command: Command) -> Result<DeviceEvent, DeviceError> {
+        Device::query(self, command)
+    }
+
+    fn apply(&mut self, setting: Command) -> Result<Option<DeviceEvent>, DeviceError> {
+        Device::apply(self, setting)
+    }
+
+    fn next_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DeviceError> {
+        Device::next_event(self, timeout)
+    }
+
+    fn toggle_mute(&mut self) -> Result<bool, DeviceError> {
+        let DeviceEvent::Muted(muted) = Device::query(self, Command::GetMute)? else {
+            return Err(DeviceError::NoResponse());
+        };
+        match Device::apply(self, Command::SetMute(!muted))? {
+            Some(DeviceEvent::Muted(muted)) => Ok(muted),
+            _ => Err(DeviceError::NoResponse()),
+        }
+    }
+}
+
+// The devices returned by `connect_device` and friends.
+impl Headset for Box<dyn Device> {
+    fn capabilities(&self) -> Capabilities {
+        Headset::capabilities(self.as_ref())
+    }
+
+    fn supports(&self, command: Command) -> bool {
+        Headset::supports(self.as_ref(), command)
+    }
+
+    fn get_device_state(&self) -> &DeviceState {
+        Headset::get_device_state(self.as_ref())
+    }
+
+    fn get_device_state_mut(&mut self) -> &mut DeviceState {
+        Headset::get_device_state_mut(self.as_mut())
+    }
+
+    fn refresh_state(&mut self) -> Result<(), DeviceError> {
+        Headset::refresh_state(self.as_mut())
+    }
+
+    fn query(&mut self, command: Command) -> Result<DeviceEvent, DeviceError> {
+        Headset::query(self.as_mut(), command)
+    }
+
+    fn apply(&mut self, setting: Command) -> Result<Option<DeviceEvent>, DeviceError> {
+        Headset::apply(self.as_mut(), setting)
+    }
+
+    fn next_event(&mut self, timeout: Duration) -> Result<Option<DeviceEvent>, DeviceError> {
+        Headset::next_event(self.as_mut(), timeout)
+    }
+
+    fn toggle_mute(&mut self) -> Result<bool, DeviceError> {
+        Headset::toggle_mute(self.as_mut())
+    }
+}
+
+/// What `receive_answer` read before the timeout elapsed.
+enum Received {
+    Answer(DeviceEvent),
+    /// Only events not answering the command, which were queued.
+    Other,
+    Nothing,
+}
+
+/// Reads responses for up to `timeout` until one answers `command`.
+/// Unrelated events are applied to the state and queued for `wait_for_updates`.
+fn receive_answer(
+    device: &mut (impl Device + ?Sized),
+    command: Command,
+    timeout: Duration,
+) -> Result<Received, DeviceError> {
+    let deadline = Instant::now() + timeout;
+    let mut received = Received::Nothing;
+    let mut buf = [0u8; 8];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let res = device
+            .get_device_state()
+            .transport
+            .read_timeout(&mut buf[..], remaining)?;
+        if res == 0 {
+            break;
+        }
+        match device.get_event_from_device_response(&buf[0..res]) {
+            Some(event) if command.is_answered_by(&event) => return Ok(Received::Answer(event)),
+            Some(event) => {
+                let state = device.get_device_state_mut();
+                state.update_self_with_event(&event);
+                if state.pending_events.len() == MAX_PENDING_EVENTS {
+                    state.pending_events.pop_front();
+                }
+                state.pending_events.push_back(event);
+                received = Received::Other;
+            }
+            None => (),
+        }
+    }
+    Ok(received)
+}
+
+#[test]
+fn test_refresh_state_with_in_memory_transport() {
+    use std::sync::Mutex;
+
+    // Answers every request with the battery level, which is what a headset on the dongle would do for the battery query.
+    #[derive(Debug, Default)]
+    struct InMemoryTransport {
+        last_request: Mutex<Option<Vec<u8>>>,
+    }
+
+    impl Transport for InMemoryTransport {
+        fn write(&self, data: &[u8]) -> Result<usize, DeviceError> {
+            *self.last_request.lock().unwrap() = Some(data.to_vec());
+            Ok(data.len())
+        }
+
+        fn read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, DeviceError> {
+            match self.last_request.lock().unwrap().take() {
+                Some(request) if request[3] == 2 => {
+                    buf[..8].copy_from_slice(&[0x06, 0xff, 0xbb, 2, 0, 0, 0, 42]);
+                    Ok(8)
+                }
+                _ => Ok(0),
+            }
+        }
+
+        fn get_product_string(&self) -> Result<Option<String>, DeviceError> {
+            Ok(Some("HyperX Cloud II Wireless".to_string()))
+        }
+    }
+
+    let state = DeviceState::from_transport(Box::new(InMemoryTransport::default()), 0x018B, 0x03F0)
+        .unwrap();
+    let mut device = connect_with_descriptors(state, &descriptor::built_in_descriptors()).unwrap();
+    assert!(device.refresh_state().is_ok());
+    assert_eq!(device.get_device_state().battery_level, Some(42));
+    assert_eq!(
+        device.get_device_state().device_name.as_deref(),
+        Some("HyperX Cloud II Wireless")
+    );
+}
+
+#[test]
+fn test_query_queues_unrelated_events() {
+    use crate::devices::mock::MockCloudIIWireless;
+
+    let headset = MockCloudIIWireless::new();
+    headset.set_battery_level(80);
+    let mut device = headset.connect().unwrap();
+    headset.send_event(DeviceEvent::Muted(true));
+    assert!(matches!(
+        device.query(Command::GetBattery),
+        Ok(DeviceEvent::BatterLevel(80))
+    ));
+    assert_eq!(device.get_device_state().muted, Some(true));
+    assert!(matches!(
+        device.wait_for_updates(Duration::from_secs(1)),
+        Some(DeviceEvent::Muted(true))
+    ));
+    assert!(matches!(
+        device.query(Command::GetVoicePrompt),
+        Err(DeviceError::NotSupported(Command::GetVoicePrompt))
+    ));
+}
+
+#[test]
+fn test_apply_reads_the_setting_back() {
+    use crate::devices::mock::MockCloudIIWireless;
+
+    let headset = MockCloudIIWireless::new();
+    let mut device = headset.connect().unwrap();
+    assert_eq!(
+        device.apply(Command::SetSideToneVolume(7)).unwrap(),
+        Some(DeviceEvent::SideToneVolume(7))
+    );
+    assert_eq!(device.get_device_state().side_tone_volume, Some(7));
+    assert_eq!(
+        device
+            .apply(Command::SetAutomaticShutdown(Duration::from_secs(90)))
+            .unwrap(),
+        Some(DeviceEvent::AutomaticShutdownAfter(Duration::from_secs(60)))
+    );
+
+    headset.lock_settings();
+    assert!(matches!(
+        device.apply(Command::SetMute(true)),
+        Err(DeviceError::SettingNotApplied {
+            expected: DeviceEvent::Muted(true),
+            actual: DeviceEvent::Muted(false),
+        })
+    ));
+    assert_eq!(device.get_device_state().muted, Some(false));
+}
+
+#[test]
+fn test_capabilities_of_cloud_ii() {
+    use crate::devices::mock::MockCloudIIWireless;
+
+    let device = MockCloudIIWireless::new().connect().unwrap();
+    let capabilities = device.capabilities();
+    assert!(capabilities.battery.is_read_only());
+    assert!(capabilities.charging.is_read_only());
+    assert!(capabilities.mute.readable && capabilities.mute.writable);
+    assert!(capabilities.automatic_shutdown.writable);
+    assert!(!capabilities.voice_prompt.is_supported());
+    assert!(!capabilities.product_color.is_supported());
+}
+
+#[test]
+fn test_serialize_events() {
+    use crate::devices::mock::MockCloudIIWireless;
+
+    let event = DeviceEvent::AutomaticShutdownAfter(Duration::from_secs(30 * 60));
+    assert_eq!(
+        serde_json::to_string(&event).unwrap(),
+        r#"{"event":"automatic_shutdown_minutes","value":30}"#
+    );
+    assert_eq!(
+        serde_json::to_string(&DeviceEvent::BatterLevel(42)).unwrap(),
+        r#"{"event":"battery_level","value":42}"#
+    );
+    let event: DeviceEvent =
+        serde_json::from_str(r#"{"event":"charging","value":"fully_charged"}"#).unwrap();
+    assert!(matches!(event, DeviceEvent::Charging(ChargingStatus::FullyCharged)));
+
+    let device = MockCloudIIWireless::new().connect().unwrap();
+    let state = serde_json::to_value(device.get_device_state()).unwrap();
+    assert_eq!(state["battery_level"], serde_json::Value::Null);
+    assert!(state.get("transport").is_none());
+}
+
+#[test]
+fn test_device_info_matches() {
+    let info = DeviceInfo {
+        path: "/dev/hidraw3".to_string(),
+        serial_number: Some("0A1B2C".to_string()),
+        vendor_id: 0x03F0,
+        product_id: 0x018B,
+        product_string: Some("HyperX Cloud II Wireless".to_string()),
+    };
+    assert!(info.matches("0A1B2C"));
+    assert!(info.matches("/dev/hidraw3"));
+    assert!(info.matches("03f0:018b"));
+    assert!(info.matches("03F0:018B"));
+    assert!(!info.matches("03f0:0d93"));
+    assert!(!info.matches("0A1B"));
+    assert!(!info.matches("HyperX Cloud II Wireless"));
+    let without_serial = DeviceInfo {
+        serial_number: None,
+        ..info
+    };
+    assert!(!without_serial.matches(""));
+}
+
+#[test]
+fn test_only_the_first_interface_is_listed() {
+    let mut interfaces = HashMap::new();
+    let enumerated = [
+        ((0x03F0, 0x018B), 3),
+        ((0x03F0, 0x018B), 0),
+        ((0x03F0, 0x0D93), 0),
+        // A second dongle of the same kind
+        ((0x03F0, 0x018B), 3),
+        ((0x03F0, 0x018B), 0),
+    ];
+    let listed: Vec<bool> = enumerated
+        .into_iter()
+        .map(|(ids, interface)| is_first_interface(&mut interfaces, ids, interface))
+        .collect();
+    assert_eq!(listed, [true, false, true, true, false]);
+}
@@ -0,0 +1,254 @@
+use crate::devices::transport::Transport;
+use crate::devices::DeviceError;
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// A recording is a text file with one packet per line: `<unix time in ms> <W|R> <hex bytes>`.
+// A read that timed out is logged as an `R` line without bytes.
+// Every connection starts with a header line: `# <vendor id> <product id> <product string>`.
+const WRITE: &str = "W";
+const READ: &str = "R";
+
+/// Logs every packet passing through the wrapped transport to a file.
+#[derive(Debug)]
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    file: Mutex<File>,
+}
+
+impl RecordingTransport {
+    /// Appends to `path`, so reconnecting to a headset adds a new session to the same file.
+    pub fn new(
+        inner: Box<dyn Transport>,
+        path: &Path,
+        vendor_id: u16,
+        product_id: u16,
+    ) -> Result<Self, DeviceError> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let product = inner.get_product_string()?.unwrap_or_default();
+        writeln!(file, "# {vendor_id:04x} {product_id:04x} {product}")?;
+        Ok(RecordingTransport {
+            inner,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn log(&self, direction: &str, data: &[u8]) -> Result<(), DeviceError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file, "{timestamp} {direction} {}", to_hex(data))?;
+        Ok(())
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn write(&self, data: &[u8]) -> Result<usize, DeviceError> {
+        self.log(WRITE, data)?;
+        self.inner.write(data)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceError> {
+        let len = self.inner.read_timeout(buf, timeout)?;
+        self.log(READ, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn get_product_string(&self) -> Result<Option<String>, DeviceError> {
+        self.inner.get_product_string()
+    }
+}
+
+/// Plays a file written by `RecordingTransport` back.
+/// Reads return the recorded responses in order, writes must match the next recorded write
+/// and fail with `ReplayDiverged` otherwise, so a replay catches changes in what is sent.
+/// Once the recording is over, reads wait for the timeout like a headset that doesn't answer.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    product: Option<String>,
+    packets: Mutex<VecDeque<(bool, Vec<u8>)>>,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &Path) -> Result<Self, DeviceError> {
+        let recording = std::fs::read_to_string(path)?;
+        let mut header = None;
+        let mut packets = VecDeque::new();
+        for line in recording.lines().filter(|l| !l.trim().is_empty()) {
+            if let Some(info) = line.strip_prefix("# ") {
+                if header.is_none() {
+                    header = Some(parse_header(info)?);
+                }
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (_timestamp, direction) = (fields.next(), fields.next());
+            let data = from_hex(fields.next().unwrap_or_default())
+                .ok_or_else(|| DeviceError::InvalidRecording(line.to_string()))?;
+            match direction {
+                Some(WRITE) => packets.push_back((true, data)),
+                Some(READ) => packets.push_back((false, data)),
+                _ => return Err(DeviceError::InvalidRecording(line.to_string())),
+            }
+        }
+        let (vendor_id, product_id, product) =
+            header.ok_or_else(|| DeviceError::InvalidRecording("missing header".to_string()))?;
+        Ok(ReplayTransport {
+            vendor_id,
+            product_id,
+            product,
+            packets: Mutex::new(packets),
+        })
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write(&self, data: &[u8]) -> Result<usize, DeviceError> {
+        let mut packets = self.packets.lock().unwrap_or_else(|e| e.into_inner());
+        // Timed out reads depend on timing, the next write may come before them.
+        while packets
+            .front()
+            .is_some_and(|(is_write, recorded)| !is_write && recorded.is_empty())
+        {
+            packets.pop_front();
+        }
+        match packets.front() {
+            Some((true, recorded)) if recorded == data => {
+                packets.pop_front();
+                Ok(data.len())
+            }
+            Some((true, recorded)) => Err(DeviceError::ReplayDiverged(format!(
+                "wrote {} but the recording has {}",
+                to_hex(data),
+                to_hex(recorded)
+            ))),
+            Some((false, recorded)) => Err(DeviceError::ReplayDiverged(format!(
+                "wrote {} but the recording reads {} first",
+                to_hex(data),
+                to_hex(recorded)
+            ))),
+            None => Err(DeviceError::ReplayDiverged(format!(
+                "wrote {} after the end of the recording",
+                to_hex(data)
+            ))),
+        }
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceError> {
+        let mut packets = self.packets.lock().unwrap_or_else(|e| e.into_inner());
+        match packets.front() {
+            Some((false, data)) => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                packets.pop_front();
+                Ok(len)
+            }
+            Some(_) => Ok(0),
+            None => {
+                drop(packets);
+                std::thread::sleep(timeout);
+                Ok(0)
+            }
+        }
+    }
+
+    fn get_product_string(&self) -> Result<Option<String>, DeviceError> {
+        Ok(self.product.clone())
+    }
+}
+
+fn parse_header(info: &str) -> Result<(u16, u16, Option<String>), DeviceError> {
+    let invalid = || DeviceError::InvalidRecording(format!("# {info}"));
+    let mut fields = info.splitn(3, ' ');
+    let vendor_id = fields
+        .next()
+        .and_then(|v| u16::from_str_radix(v, 16).ok())
+        .ok_or_else(invalid)?;
+    let product_id = fields
+        .next()
+        .and_then(|p| u16::from_str_radix(p, 16).ok())
+        .ok_or_else(invalid)?;
+    let product = fields.next().filter(|p| !p.is_empty()).map(str::to_string);
+    Ok((vendor_id, product_id, product))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[test]
+fn test_record_and_replay() {
+    use crate::devices::descriptor::built_in_descriptors;
+    use crate::devices::mock::MockCloudIIWireless;
+    use crate::devices::{connect_replay, connect_with_descriptors, DeviceState};
+
+    let path = std::env::temp_dir().join(format!("hyper_headset_{}.rec", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let headset = MockCloudIIWireless::new();
+    headset.set_battery_level(73);
+    headset.unplug_mic();
+    let state = DeviceState::from_transport(Box::new(headset), 0x018B, 0x03F0)
+        .and_then(|state| state.record_to(&path))
+        .unwrap();
+    connect_with_descriptors(state, &built_in_descriptors())
+        .and_then(|mut device| device.refresh_state())
+        .unwrap();
+
+    let mut replayed = connect_replay(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    replayed.refresh_state().unwrap();
+    assert_eq!(replayed.get_device_state().battery_level, Some(73));
+    assert_eq!(replayed.get_device_state().mic_connected, Some(false));
+    assert_eq!(replayed.get_device_state().vendor_id, 0x03F0);
+}
+
+#[test]
+fn test_replay_rejects_other_writes() {
+    let path =
+        std::env::temp_dir().join(format!("hyper_headset_{}.diverged.rec", std::process::id()));
+    std::fs::write(
+        &path,
+        "# 03f0 018b HyperX\n1 W 0601\n2 R\n3 R 0602\n4 W 0603\n",
+    )
+    .unwrap();
+    let replay = ReplayTransport::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(replay.write(&[0x06, 0x01]).unwrap(), 2);
+    assert!(matches!(
+        replay.write(&[0x06, 0x03]),
+        Err(DeviceError::ReplayDiverged(_))
+    ));
+    let mut buf = [0; 8];
+    assert_eq!(replay.read_timeout(&mut buf, Duration::ZERO).unwrap(), 2);
+    assert!(matches!(
+        replay.write(&[0x06, 0x04]),
+        Err(DeviceError::ReplayDiverged(_))
+    ));
+    assert_eq!(replay.write(&[0x06, 0x03]).unwrap(), 2);
+    assert!(matches!(
+        replay.write(&[0x06, 0x03]),
+        Err(DeviceError::ReplayDiverged(_))
+    ));
+}
@@ -0,0 +1,45 @@
+use crate::devices::DeviceError;
+use hidapi::HidDevice;
+use std::{fmt::Debug, time::Duration};
+
+/// The raw channel packets are exchanged over.
+/// The hidapi device is the only real backend, other implementations exist to drive a `Device` without a headset.
+pub trait Transport: Debug + Send {
+    fn write(&self, data: &[u8]) -> Result<usize, DeviceError>;
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceError>;
+    fn get_product_string(&self) -> Result<Option<String>, DeviceError>;
+}
+
+/// A transport without a headset behind it, for a `DeviceState` that is only used for its values.
+#[derive(Debug)]
+pub struct NullTransport;
+
+impl Transport for NullTransport {
+    fn write(&self, _data: &[u8]) -> Result<usize, DeviceError> {
+        Err(DeviceError::NoDeviceFound())
+    }
+
+    fn read_timeout(&self, _buf: &mut [u8], _timeout: Duration) -> Result<usize, DeviceError> {
+        Err(DeviceError::NoDeviceFound())
+    }
+
+    fn get_product_string(&self) -> Result<Option<String>, DeviceError> {
+        Ok(None)
+    }
+}
+
+impl Transport for HidDevice {
+    fn write(&self, data: &[u8]) -> Result<usize, DeviceError> {
+        Ok(HidDevice::write(self, data)?)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceError> {
+        // A negative timeout would block forever
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        Ok(HidDevice::read_timeout(self, buf, timeout)?)
+    }
+
+    fn get_product_string(&self) -> Result<Option<String>, DeviceError> {
+        Ok(HidDevice::get_product_string(self)?)
+    }
+}
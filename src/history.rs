@@ -0,0 +1,540 @@
+use crate::config::state_dir;
+use crate::devices::{ChargingStatus, DeviceError, DeviceInfo, DeviceState};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Old enough for a trend over weeks, a sample is only stored when the level changes.
+const RETENTION: Duration = Duration::from_secs(180 * 24 * 60 * 60);
+// Samples further apart weren't taken while the tray watched the headset the whole time.
+const MAX_GAP: Duration = Duration::from_secs(2 * 60 * 60);
+// A shorter run says little about the rate, a single percent can take 20 minutes.
+const MIN_RUN: Duration = Duration::from_secs(30 * 60);
+const MIN_CHANGE: u8 = 2;
+// A cycle has to use this many percent before its full charge runtime is extrapolated.
+const MIN_CYCLE_USE: u64 = 10;
+const WEEK: u64 = 7 * 24 * 60 * 60;
+const MONTH: u64 = 30 * 24 * 60 * 60;
+// 1970-01-05, the epoch was a Thursday.
+const FIRST_MONDAY: u64 = 4 * 24 * 60 * 60;
+
+/// One battery reading, a line of JSON in the history file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatterySample {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    /// `None` from the moment the headset was turned off or disconnected.
+    pub level: Option<u8>,
+    pub charging: ChargingStatus,
+}
+
+/// The battery levels of one headset over time, appended to a file by the tray.
+#[derive(Debug, Default)]
+pub struct BatteryHistory {
+    path: Option<PathBuf>,
+    samples: Vec<BatterySample>,
+}
+
+impl BatteryHistory {
+    /// The history in `history_path`, empty if the headset has none yet.
+    pub fn for_device(info: &DeviceInfo) -> Result<Self, DeviceError> {
+        match history_path(info) {
+            Some(path) => BatteryHistory::load(&path),
+            None => Ok(BatteryHistory::default()),
+        }
+    }
+
+    /// Reads the samples in `path`, samples older than the retention period are removed from the file.
+    pub fn load(path: &Path) -> Result<Self, DeviceError> {
+        let history = match std::fs::read_to_string(path) {
+            Ok(history) => history,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let samples = parse(&history);
+        let oldest = unix_time(SystemTime::now()).saturating_sub(RETENTION.as_secs());
+        let kept: Vec<BatterySample> = samples
+            .iter()
+            .copied()
+            .filter(|sample| sample.time >= oldest)
+            .collect();
+        let history = BatteryHistory {
+            path: Some(path.to_path_buf()),
+            samples: kept,
+        };
+        if history.samples.len() < samples.len() {
+            history.rewrite()?;
+        }
+        Ok(history)
+    }
+
+    /// Reads the samples in `path` without changing the file, for reports on a copied history.
+    pub fn read(path: &Path) -> Result<Self, DeviceError> {
+        Ok(BatteryHistory {
+            path: None,
+            samples: parse(&std::fs::read_to_string(path)?),
+        })
+    }
+
+    pub fn samples(&self) -> &[BatterySample] {
+        &self.samples
+    }
+
+    /// Adds a sample if the battery level or the charging status changed and appends it to the file.
+    pub fn record(&mut self, state: &DeviceState, now: SystemTime) -> Result<(), DeviceError> {
+        let sample = BatterySample {
+            time: unix_time(now),
+            level: state
+                .battery_level
+                .filter(|_| state.connected != Some(false)),
+            charging: state.charging.unwrap_or(ChargingStatus::NotCharging),
+        };
+        let unchanged = match self.samples.last() {
+            Some(last) => (last.level, last.charging) == (sample.level, sample.charging),
+            None => sample.level.is_none(),
+        };
+        if unchanged {
+            return Ok(());
+        }
+        self.samples.push(sample);
+        if let Some(path) = &self.path {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", to_line(&sample)?)?;
+        }
+        Ok(())
+    }
+
+    /// Fills in `time_remaining` while discharging and `time_to_full` while charging,
+    /// from the rate of the current run or, if it's too short so far, the earlier runs.
+    pub fn update_estimates(&self, state: &mut DeviceState, now: SystemTime) {
+        state.time_remaining = None;
+        state.time_to_full = None;
+        let Some(level) = state
+            .battery_level
+            .filter(|_| state.connected != Some(false))
+        else {
+            return;
+        };
+        match state.charging.unwrap_or(ChargingStatus::NotCharging) {
+            ChargingStatus::NotCharging => {
+                state.time_remaining =
+                    self.rate(ChargingStatus::NotCharging, now)
+                        .map(|(change, seconds)| {
+                            Duration::from_secs(u64::from(level) * seconds / change)
+                        });
+            }
+            ChargingStatus::Charging => {
+                let missing = u64::from(100 - level.min(100));
+                state.time_to_full = self
+                    .rate(ChargingStatus::Charging, now)
+                    .map(|(change, seconds)| Duration::from_secs(missing * seconds / change));
+            }
+            _ => (),
+        }
+    }
+
+    /// Uninterrupted stretches of samples with the same charging status.
+    pub fn runs(&self) -> impl Iterator<Item = &[BatterySample]> {
+        self.samples
+            .chunk_by(|a, b| {
+                a.level.is_some()
+                    && b.level.is_some()
+                    && a.charging == b.charging
+                    && b.time.saturating_sub(a.time) <= MAX_GAP.as_secs()
+            })
+            .filter(|run| run[0].level.is_some())
+    }
+
+    /// How many percent the level changed in how many seconds while `charging`.
+    fn rate(&self, charging: ChargingStatus, now: SystemTime) -> Option<(u64, u64)> {
+        let now = unix_time(now);
+        let runs: Vec<&[BatterySample]> = self
+            .runs()
+            .filter(|run| run[0].charging == charging)
+            .collect();
+        // The run still going on counts up to now, the next percent may be about to drop.
+        let current = runs.last().filter(|run| {
+            let last = &run[run.len() - 1];
+            self.samples
+                .last()
+                .is_some_and(|sample| std::ptr::eq(sample, last))
+                && now.saturating_sub(last.time) <= MAX_GAP.as_secs()
+        });
+        if let Some(rate) = current.and_then(|run| run_change(run, now)) {
+            return Some(rate);
+        }
+        let (change, seconds) = runs
+            .iter()
+            .filter_map(|run| run_change(run, run[run.len() - 1].time))
+            .fold((0, 0), |(change, seconds), (c, s)| {
+                (change + c, seconds + s)
+            });
+        (change > 0).then_some((change, seconds))
+    }
+
+    /// Sums up the discharges between charges, see `BatteryReport`.
+    pub fn report(&self) -> BatteryReport {
+        let mut cycles: Vec<Cycle> = Vec::new();
+        let mut charges = 0;
+        let mut discharging = false;
+        for run in self.runs() {
+            let (first, last) = (run[0], run[run.len() - 1]);
+            match first.charging {
+                ChargingStatus::NotCharging => {
+                    if !discharging {
+                        cycles.push(Cycle {
+                            start: first.time,
+                            ..Default::default()
+                        });
+                        discharging = true;
+                    }
+                    if let (Some(cycle), Some(from), Some(to)) =
+                        (cycles.last_mut(), first.level, last.level)
+                    {
+                        cycle.used_percent += u64::from(from.saturating_sub(to));
+                        // The clock may have been set back in between.
+                        cycle.runtime += last.time.saturating_sub(first.time);
+                    }
+                }
+                ChargingStatus::Charging | ChargingStatus::FullyCharged => {
+                    if discharging || charges == 0 {
+                        charges += 1;
+                    }
+                    discharging = false;
+                }
+                ChargingStatus::ChargeError => (),
+            }
+        }
+        let used_percent: u64 = cycles.iter().map(|cycle| cycle.used_percent).sum();
+        let cycles: Vec<CycleReport> = cycles.iter().map(Cycle::report).collect();
+
+        let mut weeks: Vec<WeekReport> = Vec::new();
+        for cycle in &cycles {
+            let week_start = cycle.start - cycle.start.saturating_sub(FIRST_MONDAY) % WEEK;
+            match weeks.last_mut() {
+                Some(week) if week.start == week_start => week.cycles.push(*cycle),
+                _ => weeks.push(WeekReport {
+                    start: week_start,
+                    cycles: vec![*cycle],
+                    full_charge_runtime_minutes: None,
+                }),
+            }
+        }
+        for week in &mut weeks {
+            week.full_charge_runtime_minutes = average_runtime(&week.cycles);
+        }
+
+        BatteryReport {
+            charge_cycles: used_percent as f64 / 100.0,
+            charges,
+            full_charge_runtime_minutes: average_runtime(&cycles),
+            runtime_trend_percent_per_month: runtime_trend(&cycles),
+            weeks,
+        }
+    }
+
+    fn rewrite(&self) -> Result<(), DeviceError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut lines = String::new();
+        for sample in &self.samples {
+            lines.push_str(&to_line(sample)?);
+            lines.push('\n');
+        }
+        std::fs::write(path, lines)?;
+        Ok(())
+    }
+}
+
+/// Long-term figures of a battery, for telling which headsets need replacing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatteryReport {
+    /// Full charges used up, two discharges from 100% to 50% are one cycle.
+    pub charge_cycles: f64,
+    /// How often the headset was plugged in to charge.
+    pub charges: usize,
+    /// Average over the cycles that used enough of the battery to tell.
+    pub full_charge_runtime_minutes: Option<u64>,
+    /// How much the full charge runtime changes in 30 days, negative as the battery wears out.
+    pub runtime_trend_percent_per_month: Option<f64>,
+    pub weeks: Vec<WeekReport>,
+}
+
+/// The cycles that started in one week.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WeekReport {
+    /// The Monday the week starts on, in seconds since the Unix epoch.
+    pub start: u64,
+    pub full_charge_runtime_minutes: Option<u64>,
+    pub cycles: Vec<CycleReport>,
+}
+
+/// The discharge between two charges, only counting the time the headset was on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CycleReport {
+    /// Seconds since the Unix epoch.
+    pub start: u64,
+    pub used_percent: u64,
+    pub runtime_minutes: u64,
+    /// How long a full charge would have lasted at the rate of this cycle.
+    pub full_charge_runtime_minutes: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Cycle {
+    start: u64,
+    used_percent: u64,
+    /// Seconds
+    runtime: u64,
+}
+
+impl Cycle {
+    fn report(&self) -> CycleReport {
+        CycleReport {
+            start: self.start,
+            used_percent: self.used_percent,
+            runtime_minutes: self.runtime / 60,
+            full_charge_runtime_minutes: (self.used_percent >= MIN_CYCLE_USE)
+                .then(|| self.runtime * 100 / self.used_percent / 60),
+        }
+    }
+}
+
+fn average_runtime(cycles: &[CycleReport]) -> Option<u64> {
+    let runtimes: Vec<u64> = cycles
+        .iter()
+        .filter_map(|cycle| cycle.full_charge_runtime_minutes)
+        .collect();
+    (!runtimes.is_empty()).then(|| runtimes.iter().sum::<u64>() / runtimes.len() as u64)
+}
+
+/// The slope of a least squares fit through the full charge runtimes, relative to their average.
+fn runtime_trend(cycles: &[CycleReport]) -> Option<f64> {
+    let points: Vec<(f64, f64)> = cycles
+        .iter()
+        .filter_map(|cycle| {
+            Some((
+                cycle.start as f64,
+                cycle.full_charge_runtime_minutes? as f64,
+            ))
+        })
+        .collect();
+    let (first, last) = (points.first()?.0, points.last()?.0);
+    if points.len() < 3 || last - first < WEEK as f64 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if mean_y == 0.0 {
+        return None;
+    }
+    let per_second = covariance / variance;
+    Some(per_second * MONTH as f64 / mean_y * 100.0)
+}
+
+/// How much the level changed over a run ending at `end`, `None` if the run is too short to tell.
+fn run_change(run: &[BatterySample], end: u64) -> Option<(u64, u64)> {
+    let first = run.first()?.level?;
+    let last = run.last()?.level?;
+    let change = first.abs_diff(last);
+    let seconds = end.saturating_sub(run[0].time);
+    (change >= MIN_CHANGE && seconds >= MIN_RUN.as_secs()).then_some((u64::from(change), seconds))
+}
+
+fn to_line(sample: &BatterySample) -> Result<String, DeviceError> {
+    serde_json::to_string(sample).map_err(|e| DeviceError::IoError(std::io::Error::other(e)))
+}
+
+/// A line cut off by a crash is skipped.
+fn parse(history: &str) -> Vec<BatterySample> {
+    history
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `~/.local/state/hyper_headset/battery/<serial number>.jsonl`, the IDs stand in for a missing serial number.
+pub fn history_path(info: &DeviceInfo) -> Option<PathBuf> {
+    let name = match &info.serial_number {
+        Some(serial_number) => serial_number.replace(['/', '\\'], "_"),
+        None => format!("{:04x}_{:04x}", info.vendor_id, info.product_id),
+    };
+    Some(state_dir()?.join("battery").join(format!("{name}.jsonl")))
+}
+
+/// The `history_path` of a headset that isn't plugged in, `selector` is its serial number
+/// or its vendor and product ID as `03f0:018b`, see `DeviceInfo::matches`.
+pub fn history_path_for_selector(selector: &str) -> Option<PathBuf> {
+    let ids = selector.split_once(':').and_then(|(vendor_id, product_id)| {
+        let id = |id: &str| u16::from_str_radix(id, 16).ok().filter(|_| id.len() == 4);
+        Some((id(vendor_id)?, id(product_id)?))
+    });
+    let (vendor_id, product_id) = ids.unwrap_or_default();
+    history_path(&DeviceInfo {
+        path: String::new(),
+        serial_number: ids.is_none().then(|| selector.to_string()),
+        vendor_id,
+        product_id,
+        product_string: None,
+    })
+}
+
+#[test]
+fn test_estimates() {
+    let state = &mut DeviceState::without_transport(0x018B, 0x03F0);
+    state.connected = Some(true);
+    state.charging = Some(ChargingStatus::NotCharging);
+    let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let minutes = |m: u64| start + Duration::from_secs(m * 60);
+
+    // 1% every 12 minutes, 5% an hour.
+    let mut history = BatteryHistory::default();
+    for (i, level) in (80..=90).rev().enumerate() {
+        state.battery_level = Some(level);
+        history.record(state, minutes(i as u64 * 12)).unwrap();
+        history.record(state, minutes(i as u64 * 12 + 5)).unwrap();
+    }
+    assert_eq!(history.samples().len(), 11);
+    history.update_estimates(state, minutes(120));
+    assert_eq!(
+        state.time_remaining,
+        Some(Duration::from_secs(16 * 60 * 60))
+    );
+    assert_eq!(state.time_to_full, None);
+
+    // Too short to tell, the earlier run is used.
+    state.connected = Some(false);
+    history.record(state, minutes(130)).unwrap();
+    history.update_estimates(state, minutes(130));
+    assert_eq!(state.time_remaining, None);
+    state.connected = Some(true);
+    state.battery_level = Some(50);
+    history.record(state, minutes(600)).unwrap();
+    history.update_estimates(state, minutes(610));
+    assert_eq!(
+        state.time_remaining,
+        Some(Duration::from_secs(10 * 60 * 60))
+    );
+
+    // 1% a minute.
+    state.charging = Some(ChargingStatus::Charging);
+    for level in 50..=80 {
+        state.battery_level = Some(level);
+        history
+            .record(state, minutes(700 + u64::from(level) - 50))
+            .unwrap();
+    }
+    history.update_estimates(state, minutes(730));
+    assert_eq!(state.time_remaining, None);
+    assert_eq!(state.time_to_full, Some(Duration::from_secs(20 * 60)));
+}
+
+#[test]
+fn test_battery_report() {
+    let mut history = BatteryHistory::default();
+    // Monday, 2023-11-13
+    let monday = 1_699_833_600;
+    let mut discharge = |start: u64, seconds_per_percent: u64| {
+        for (i, level) in (50..=100).rev().enumerate() {
+            history.samples.push(BatterySample {
+                time: start + i as u64 * seconds_per_percent,
+                level: Some(level),
+                charging: ChargingStatus::NotCharging,
+            });
+        }
+        let end = start + 50 * seconds_per_percent;
+        for (time, charging) in [
+            (end + 60, ChargingStatus::Charging),
+            (end + 3600, ChargingStatus::FullyCharged),
+        ] {
+            history.samples.push(BatterySample {
+                time,
+                level: Some(100),
+                charging,
+            });
+        }
+    };
+    // 15 h for half the battery, then 13.5 h and 12 h.
+    discharge(monday + 3600, 18 * 60);
+    discharge(monday + WEEK + 3600, 972);
+    discharge(monday + 2 * WEEK + 3600, 864);
+
+    let report = history.report();
+    assert_eq!(report.charge_cycles, 1.5);
+    assert_eq!(report.charges, 3);
+    assert_eq!(report.weeks.len(), 3);
+    assert_eq!(report.weeks[0].start, monday);
+    assert_eq!(report.weeks[0].cycles[0].used_percent, 50);
+    assert_eq!(report.weeks[0].cycles[0].runtime_minutes, 15 * 60);
+    assert_eq!(report.weeks[0].full_charge_runtime_minutes, Some(30 * 60));
+    assert_eq!(report.weeks[2].full_charge_runtime_minutes, Some(24 * 60));
+    assert_eq!(report.full_charge_runtime_minutes, Some(27 * 60));
+    let trend = report.runtime_trend_percent_per_month.unwrap();
+    assert!((-50.0..-40.0).contains(&trend), "{trend}");
+}
+
+#[test]
+fn test_report_with_clock_set_back() {
+    let mut history = BatteryHistory::default();
+    for (time, level) in [(1_700_000_000, 90), (1_700_001_200, 85), (1_699_999_400, 80)] {
+        history.samples.push(BatterySample {
+            time,
+            level: Some(level),
+            charging: ChargingStatus::NotCharging,
+        });
+    }
+    let report = history.report();
+    assert_eq!(report.charge_cycles, 0.1);
+    assert_eq!(report.weeks[0].cycles[0].runtime_minutes, 0);
+
+    // Cycles too short to tell the runtime don't count towards the trend.
+    let cycles: Vec<CycleReport> = (0..3)
+        .map(|week| CycleReport {
+            start: week * WEEK,
+            used_percent: 50,
+            runtime_minutes: 0,
+            full_charge_runtime_minutes: Some(0),
+        })
+        .collect();
+    assert_eq!(runtime_trend(&cycles), None);
+}
+
+#[test]
+fn test_history_path_for_selector() {
+    let Some(dir) = state_dir() else {
+        return;
+    };
+    let dir = dir.join("battery");
+    assert_eq!(
+        history_path_for_selector("03f0:018b"),
+        Some(dir.join("03f0_018b.jsonl"))
+    );
+    assert_eq!(
+        history_path_for_selector("0A1B/2C"),
+        Some(dir.join("0A1B_2C.jsonl"))
+    );
+    assert_eq!(
+        history_path_for_selector("3f0:18b"),
+        Some(dir.join("3f0:18b.jsonl"))
+    );
+}
@@ -1 +1,6 @@
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
 pub mod devices;
+pub mod history;
+pub mod monitor;
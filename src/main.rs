@@ -1,12 +1,36 @@
-use std::time::Duration;
-use clap::{Arg, Command};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+use clap::{Arg, ArgMatches, Command};
 
+mod dbus_service;
+mod mute_sync;
+mod notifications;
 mod status_tray;
-use hyper_headset::devices::connect_compatible_device;
+mod tray_icon;
+use dbus_service::DbusService;
+use mute_sync::MuteSyncThread;
+use notifications::Notifier;
+use hyper_headset::config::{config_path, load_config, profile_path, Config};
+use hyper_headset::daemon::{server::Server, socket_path, ServedDevice};
+use hyper_headset::devices::{
+    connect_to_state, list_compatible_devices, DeviceError, DeviceEvent, DeviceInfo, DeviceState,
+};
+use hyper_headset::history::BatteryHistory;
+use hyper_headset::monitor::{Action, Monitor};
 use status_tray::{StatusTray, TrayHandler};
 
-fn main() {
-    let matches = Command::new(env!("CARGO_PKG_NAME"))
+/// How long requests wait at most before the device thread picks them up.
+const REQUEST_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn cli() -> Command {
+    Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about("A tray application for monitoring HyperX headsets.")
@@ -15,35 +39,358 @@ fn main() {
                 .long("refresh_interval")
                 .required(false)
                 .help(
-                    "Set the refresh interval (in seconds)",
+                    "Set the interval (in seconds) in which the battery level is queried.\nOther changes are picked up as they happen. Defaults to 60.",
                 )
                 .value_parser(clap::value_parser!(u64)),
-        ).get_matches();
-    let refresh_interval = *matches.get_one::<u64>("refresh_interval").unwrap_or(&3);
-    let refresh_interval = Duration::from_secs(refresh_interval);
-    let tray_handler = TrayHandler::new(StatusTray::new());
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .required(false)
+                .help("Log every packet exchanged with the headset to a file.")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("notify_battery_levels")
+                .long("notify_battery_levels")
+                .required(false)
+                .help("Battery levels (in percent) that show a desktop notification when the battery drops to them.\nDefaults to 20,10,5.")
+                .value_delimiter(',')
+                .value_parser(clap::value_parser!(u8).range(0..=100)),
+        )
+        .arg(
+            Arg::new("no_notifications")
+                .long("no_notifications")
+                .required(false)
+                .help("Don't show desktop notifications for a low battery, charging or a lost connection.")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sync_mute")
+                .long("sync_mute")
+                .required(false)
+                .help("Mute the microphone of the headset in PulseAudio or PipeWire when the headset is muted, and the other way around.")
+                .action(clap::ArgAction::SetTrue),
+        )
+}
+
+fn main() {
+    let matches = cli().get_matches();
+    let record = matches.get_one::<PathBuf>("record").cloned();
+    let config = Arc::new(RwLock::new(effective_config(&matches)));
+    let mut last_modified = config_modified();
+    let recording = Arc::new(AtomicBool::new(false));
+    let dbus = DbusService::start()
+        .inspect_err(|e| eprintln!("Not publishing the headsets on D-Bus: {e}"))
+        .ok();
+    let server = socket_path().and_then(|path| {
+        Server::start(&path)
+            .inspect_err(|e| eprintln!("Not serving the headsets on {}: {e}", path.display()))
+            .ok()
+    });
+    let mut next_id = 0;
+    // Paths of the devices that currently have their own tray
+    let active_devices = Arc::new(Mutex::new(HashSet::new()));
+    // Only shown while no device is plugged in
+    let mut placeholder = None;
     loop {
-        let mut device = loop {
-            match connect_compatible_device() {
-                Ok(d) => break d,
-                Err(e) => println!("Connecting failed with error: {e}"),
+        // Picks up changes of the config file, the device threads apply them.
+        if last_modified != config_modified() {
+            last_modified = config_modified();
+            println!("Reloading the config file");
+            *config.write().unwrap() = effective_config(&matches);
+        }
+        let selector = config.read().unwrap().device.clone();
+        match list_compatible_devices() {
+            Ok(devices) => {
+                for info in devices {
+                    if selector.as_ref().is_some_and(|selector| !info.matches(selector)) {
+                        continue;
+                    }
+                    if !active_devices.lock().unwrap().insert(info.path.clone()) {
+                        continue;
+                    }
+                    let active_devices = active_devices.clone();
+                    // Recordings of several devices can't be told apart, so only one device is recorded.
+                    let record = record
+                        .clone()
+                        .filter(|_| !recording.swap(true, Ordering::SeqCst));
+                    let recording = recording.clone();
+                    let dbus = dbus.clone();
+                    let server = server.clone();
+                    let config = config.clone();
+                    let id = next_id;
+                    next_id += 1;
+                    std::thread::spawn(move || {
+                        let recorded = record.is_some();
+                        run_device(&info, &config, record, id, dbus, server);
+                        if recorded {
+                            recording.store(false, Ordering::SeqCst);
+                        }
+                        active_devices.lock().unwrap().remove(&info.path);
+                    });
+                }
             }
-            std::thread::sleep(Duration::from_secs(1));
-        };
-
-        // Run loop
-        loop {
-            std::thread::sleep(refresh_interval);
-            match device.refresh_state() {
-                Ok(()) => (),
-                Err(error) => {
-                    eprintln!("{error}");
-                    device.get_device_state_mut().connected = None;
-                    tray_handler.update(device.get_device_state());
-                    break; // try to reconnect
+            Err(e) => println!("Listing devices failed with error: {e}"),
+        }
+        let no_device = active_devices.lock().unwrap().is_empty();
+        match (no_device, placeholder.is_some()) {
+            (true, false) => placeholder = Some(TrayHandler::new(StatusTray::new())),
+            (false, true) => placeholder = None,
+            _ => (),
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// The config file with the command line arguments applied on top.
+fn effective_config(matches: &ArgMatches) -> Config {
+    with_arguments(load_config(), matches)
+}
+
+/// Arguments that are given take precedence, flags that are not given leave the config as it is.
+fn with_arguments(mut config: Config, matches: &ArgMatches) -> Config {
+    if let Some(refresh_interval) = matches.get_one::<u64>("refresh_interval") {
+        config.refresh_interval = Some(*refresh_interval);
+    }
+    if let Some(levels) = matches.get_many::<u8>("notify_battery_levels") {
+        config.notify_battery_levels = Some(levels.copied().collect());
+    }
+    if matches.get_flag("no_notifications") {
+        config.notifications = Some(false);
+    }
+    if matches.get_flag("sync_mute") {
+        config.sync_mute = Some(true);
+    }
+    config
+}
+
+/// When the config file and the file holding the selected profile were last changed.
+fn config_modified() -> [Option<SystemTime>; 2] {
+    let modified = |path: Option<PathBuf>| std::fs::metadata(path?).ok()?.modified().ok();
+    [modified(config_path()), modified(profile_path())]
+}
+
+/// Everything showing the state of one device.
+struct Outputs {
+    id: usize,
+    tray: TrayHandler,
+    dbus: Option<DbusService>,
+    server: Option<Server>,
+    notifier: Notifier,
+    history: BatteryHistory,
+    mute_sync: Option<MuteSyncThread>,
+}
+
+impl Outputs {
+    /// Records the battery level and shows `state` with the estimates from the history.
+    fn update(&mut self, state: &mut DeviceState) {
+        let now = SystemTime::now();
+        if let Err(e) = self.history.record(state, now) {
+            eprintln!("Recording the battery level failed with error: {e}");
+        }
+        self.history.update_estimates(state, now);
+        let state = &*state;
+        self.tray.update(state);
+        if let (Some(mute_sync), Some(name)) = (&mut self.mute_sync, &state.device_name) {
+            mute_sync.headset_changed(name, state.muted);
+        }
+        if let Some(dbus) = &self.dbus {
+            dbus.update(self.id, state);
+        }
+        if let Some(server) = &self.server {
+            server.update(self.id, state);
+        }
+        for notification in self.notifier.check(state) {
+            // Don't hold up the device while the notification service answers.
+            std::thread::spawn(move || {
+                if let Err(e) = notifications::show(&notification) {
+                    eprintln!("Showing a notification failed with error: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// Shows the state of one device in its own tray, on D-Bus and on the socket until the device
+/// is unplugged or no longer selected by the config.
+fn run_device(
+    info: &DeviceInfo,
+    config: &RwLock<Config>,
+    record: Option<PathBuf>,
+    id: usize,
+    dbus: Option<DbusService>,
+    server: Option<Server>,
+) {
+    let mut current = config.read().unwrap().clone();
+    // Commands from D-Bus, the socket, the tray menu and the mute sync, handled between the steps of the monitor.
+    let (requests_sender, requests) = mpsc::channel();
+    let mut outputs = Outputs {
+        id,
+        tray: TrayHandler::new(StatusTray::new()),
+        dbus,
+        server,
+        notifier: Notifier::new(current.notify_battery_levels()),
+        history: BatteryHistory::for_device(info).unwrap_or_else(|e| {
+            eprintln!("Reading the battery history failed with error: {e}");
+            BatteryHistory::default()
+        }),
+        mute_sync: current
+            .sync_mute()
+            .then(|| MuteSyncThread::spawn(requests_sender.clone())),
+    };
+    outputs.notifier.set_enabled(current.notifications());
+    outputs.tray.set_profiles(&current);
+    if let Some(dbus) = &outputs.dbus {
+        dbus.add(id, requests_sender.clone());
+    }
+    'device: loop {
+        let device = DeviceState::open(info)
+            .and_then(|state| match &record {
+                Some(path) => state.record_to(path),
+                None => Ok(state),
+            })
+            .and_then(connect_to_state);
+        match device {
+            Ok(mut device) => {
+                println!("Connecting to {info}");
+                outputs
+                    .tray
+                    .set_controls(device.capabilities(), requests_sender.clone());
+                if let Some(dbus) = &outputs.dbus {
+                    dbus.set_capabilities(id, &device.capabilities());
+                }
+                if let Some(server) = &outputs.server {
+                    let served = ServedDevice::new(info.clone(), device.as_ref());
+                    server.add(id, served, requests_sender.clone());
+                }
+                // Run loop
+                let mut monitor = Monitor::new(current.refresh_interval());
+                // The settings from the config are applied whenever the headset connects.
+                let mut settings_pending = true;
+                loop {
+                    let was_connected = device.get_device_state().connected == Some(true);
+                    match monitor.step(device.as_mut(), REQUEST_POLL_INTERVAL) {
+                        Ok(true) => outputs.update(device.get_device_state_mut()),
+                        Ok(false) => (),
+                        Err(error) => {
+                            eprintln!("{error}");
+                            device.get_device_state_mut().connected = None;
+                            outputs.update(device.get_device_state_mut());
+                            break; // try to reconnect
+                        }
+                    };
+                    if !was_connected && device.get_device_state().connected == Some(true) {
+                        settings_pending = true;
+                    }
+                    for request in requests.try_iter() {
+                        if request.expired() {
+                            if let Some(reply) = request.reply {
+                                let _ = reply.send(Err(DeviceError::NoResponse()));
+                            }
+                            continue;
+                        }
+                        let result = monitor.handle(device.as_mut(), request.action);
+                        outputs.update(device.get_device_state_mut());
+                        if let (Action::ToggleMute, Ok(Some(DeviceEvent::Muted(muted)))) =
+                            (request.action, &result)
+                        {
+                            let name = device.get_device_state().device_name.clone();
+                            let muted = *muted;
+                            std::thread::spawn(move || {
+                                let name = name.as_deref().unwrap_or("Headset");
+                                if let Err(e) = notifications::show_mute(name, muted) {
+                                    eprintln!("Showing the mute state failed with error: {e}");
+                                }
+                            });
+                        }
+                        if let Some(reply) = request.reply {
+                            let _ = reply.send(result);
+                        }
+                    }
+                    if *config.read().unwrap() != current {
+                        let latest = config.read().unwrap().clone();
+                        if latest.device.as_ref().is_some_and(|selector| !info.matches(selector)) {
+                            break 'device;
+                        }
+                        monitor.set_poll_interval(latest.refresh_interval());
+                        outputs.notifier.set_thresholds(latest.notify_battery_levels());
+                        outputs.notifier.set_enabled(latest.notifications());
+                        settings_pending |= latest.active_settings() != current.active_settings();
+                        if latest.sync_mute() != outputs.mute_sync.is_some() {
+                            outputs.mute_sync = latest
+                                .sync_mute()
+                                .then(|| MuteSyncThread::spawn(requests_sender.clone()));
+                        }
+                        outputs.tray.set_profiles(&latest);
+                        current = latest;
+                    }
+                    if settings_pending && device.get_device_state().connected == Some(true) {
+                        settings_pending = false;
+                        for command in current.active_settings().commands() {
+                            match monitor.send(device.as_mut(), command) {
+                                Ok(_) | Err(DeviceError::NotSupported(_)) => (),
+                                Err(error) => eprintln!("Applying {command:?} failed: {error}"),
+                            }
+                        }
+                        outputs.update(device.get_device_state_mut());
+                    }
                 }
-            };
-            tray_handler.update(device.get_device_state())
+            }
+            Err(e) => {
+                println!("Connecting failed with error: {e}");
+                // Stale by the time the headset is back.
+                for request in requests.try_iter() {
+                    if let Some(reply) = request.reply {
+                        let _ = reply.send(Err(DeviceError::NoResponse()));
+                    }
+                }
+            }
         }
+        std::thread::sleep(Duration::from_secs(1));
+        let plugged_in = list_compatible_devices()
+            .is_ok_and(|devices| devices.iter().any(|device| device.path == info.path));
+        if !plugged_in {
+            break;
+        }
+    }
+    if let Some(dbus) = &outputs.dbus {
+        dbus.remove(id);
+    }
+    if let Some(server) = &outputs.server {
+        server.remove(id);
     }
 }
+
+#[test]
+fn test_arguments_take_precedence_over_the_config() {
+    let config = Config::from_toml(
+        r#"
+        refresh_interval = 30
+        notify_battery_levels = [15]
+        notifications = true
+        sync_mute = true
+        "#,
+    )
+    .unwrap();
+    let matches = cli()
+        .try_get_matches_from([
+            "hyper_headset",
+            "--refresh_interval",
+            "10",
+            "--notify_battery_levels",
+            "50,25",
+            "--no_notifications",
+        ])
+        .unwrap();
+    let config = with_arguments(config, &matches);
+    assert_eq!(config.refresh_interval(), Duration::from_secs(10));
+    assert_eq!(config.notify_battery_levels(), [50, 25]);
+    assert!(!config.notifications());
+    // Not given, so the config file decides.
+    assert!(config.sync_mute());
+
+    let matches = cli().try_get_matches_from(["hyper_headset"]).unwrap();
+    let file = Config::from_toml("refresh_interval = 30").unwrap();
+    assert_eq!(with_arguments(file.clone(), &matches), file);
+}
@@ -0,0 +1,204 @@
+use crate::devices::{Command, Device, DeviceError, DeviceEvent, Headset};
+use std::{
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+/// An action for a device owned by another thread, the result is sent back on `reply`.
+pub struct Request {
+    pub action: Action,
+    pub reply: Option<Sender<Result<Option<DeviceEvent>, DeviceError>>>,
+    /// Not run after this, whoever asked stopped waiting for the result.
+    pub deadline: Option<Instant>,
+}
+
+impl Request {
+    pub fn expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() > deadline)
+    }
+}
+
+/// What a `Request` asks the thread owning the device to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    Send(Command),
+    /// Flips the mute, see `Headset::toggle_mute`.
+    ToggleMute,
+}
+
+impl From<Command> for Action {
+    fn from(command: Command) -> Self {
+        Action::Send(command)
+    }
+}
+
+/// Keeps the state of a device up to date by blocking on unsolicited events,
+/// e.g. the mute button or the charging cable, and only polling slow-changing values like the battery level.
+pub struct Monitor {
+    poll_interval: Duration,
+    last_poll: Option<Instant>,
+}
+
+impl Monitor {
+    pub fn new(poll_interval: Duration) -> Self {
+        Monitor {
+            poll_interval,
+            last_poll: None,
+        }
+    }
+
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+
+    /// Waits at most `max_wait` for something to happen.
+    /// Returns `Ok(true)` if the state of the device changed.
+    /// The first step queries the complete state.
+    pub fn step(
+        &mut self,
+        device: &mut dyn Device,
+        max_wait: Duration,
+    ) -> Result<bool, DeviceError> {
+        let next_poll = match self.last_poll {
+            None => Duration::ZERO,
+            Some(last_poll) => self.poll_interval.saturating_sub(last_poll.elapsed()),
+        };
+        if next_poll.is_zero() {
+            self.poll(device)?;
+            return Ok(true);
+        }
+
+        match device.next_event(next_poll.min(max_wait))? {
+            Some(event) => {
+                self.apply(device, &event)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sends `command` and reads the changed value back, so the state reflects it right away.
+    /// Returns the value read, the answer for getters and `None` for setters without a getter.
+    /// Setters the headset didn't take fail, see `Device::apply`.
+    pub fn send(
+        &mut self,
+        device: &mut dyn Device,
+        command: Command,
+    ) -> Result<Option<DeviceEvent>, DeviceError> {
+        let Some(event) = device.apply(command)? else {
+            return Ok(None);
+        };
+        self.apply(device, &event)?;
+        Ok(Some(event))
+    }
+
+    /// Runs `action`, returning the value it read or changed like `send`.
+    pub fn handle(
+        &mut self,
+        device: &mut dyn Device,
+        action: Action,
+    ) -> Result<Option<DeviceEvent>, DeviceError> {
+        match action {
+            Action::Send(command) => self.send(device, command),
+            Action::ToggleMute => {
+                let event = DeviceEvent::Muted(Headset::toggle_mute(device)?);
+                self.apply(device, &event)?;
+                Ok(Some(event))
+            }
+        }
+    }
+
+    fn poll(&mut self, device: &mut dyn Device) -> Result<(), DeviceError> {
+        let first_poll = self.last_poll.is_none();
+        self.last_poll = Some(Instant::now());
+        if first_poll {
+            return device.refresh_state();
+        }
+        let command = match device.get_device_state().connected {
+            Some(true) => Command::GetBattery,
+            _ => Command::GetWirelessConnected,
+        };
+        let event = device.query(command)?;
+        self.apply(device, &event)
+    }
+
+    fn apply(&mut self, device: &mut dyn Device, event: &DeviceEvent) -> Result<(), DeviceError> {
+        let state = device.get_device_state_mut();
+        let was_connected = state.connected == Some(true);
+        state.update_self_with_event(event);
+        match event {
+            // The headset was turned back on, everything may have changed in the meantime.
+            DeviceEvent::WirelessConnected(true) if !was_connected => {
+                self.last_poll = Some(Instant::now());
+                device.refresh_state()
+            }
+            DeviceEvent::WirelessConnected(false) => {
+                device.get_device_state_mut().clear_state();
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_monitor_follows_events() {
+    use crate::devices::mock::MockCloudIIWireless;
+
+    let headset = MockCloudIIWireless::new();
+    let mut device = headset.connect().unwrap();
+    let mut monitor = Monitor::new(Duration::from_secs(60));
+    let wait = Duration::from_millis(10);
+
+    assert!(monitor.step(device.as_mut(), wait).unwrap());
+    assert_eq!(device.get_device_state().connected, Some(true));
+    assert!(!monitor.step(device.as_mut(), wait).unwrap());
+
+    headset.send_event(DeviceEvent::Muted(true));
+    assert!(monitor.step(device.as_mut(), wait).unwrap());
+    assert_eq!(device.get_device_state().muted, Some(true));
+
+    headset.send_event(DeviceEvent::WirelessConnected(false));
+    assert!(monitor.step(device.as_mut(), wait).unwrap());
+    assert_eq!(device.get_device_state().connected, Some(false));
+    assert_eq!(device.get_device_state().battery_level, None);
+
+    headset.set_battery_level(30);
+    headset.send_event(DeviceEvent::WirelessConnected(true));
+    assert!(monitor.step(device.as_mut(), wait).unwrap());
+    assert_eq!(device.get_device_state().connected, Some(true));
+    assert_eq!(device.get_device_state().battery_level, Some(30));
+
+    monitor.send(device.as_mut(), Command::SetMute(false)).unwrap();
+    assert_eq!(device.get_device_state().muted, Some(false));
+    assert_eq!(
+        monitor.send(device.as_mut(), Command::GetBattery).unwrap(),
+        Some(DeviceEvent::BatterLevel(30))
+    );
+
+    // The mute button was pressed, but the event got lost.
+    headset.set_muted(true);
+    assert_eq!(
+        monitor.handle(device.as_mut(), Action::ToggleMute).unwrap(),
+        Some(DeviceEvent::Muted(false))
+    );
+    assert_eq!(device.get_device_state().muted, Some(false));
+    assert_eq!(
+        monitor.handle(device.as_mut(), Action::ToggleMute).unwrap(),
+        Some(DeviceEvent::Muted(true))
+    );
+}
+
+#[test]
+fn test_request_expires() {
+    let mut request = Request {
+        action: Action::ToggleMute,
+        reply: None,
+        deadline: None,
+    };
+    assert!(!request.expired());
+    request.deadline = Some(Instant::now() + Duration::from_secs(60));
+    assert!(!request.expired());
+    request.deadline = Instant::now().checked_sub(Duration::from_millis(1));
+    assert!(request.expired());
+}
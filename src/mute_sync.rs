@@ -0,0 +1,309 @@
+// Keeps the mute button of the headset and the microphone of the sound server in sync, so meeting
+// apps show the right state. PulseAudio and PipeWire (through pipewire-pulse) are driven with `pactl`.
+use hyper_headset::devices::Command;
+use hyper_headset::monitor::Request;
+use std::{
+    error::Error,
+    process,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+/// How often the source is checked for changes made in the sound settings or a meeting app.
+const SOURCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A microphone of the sound server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub name: String,
+    /// The name of the sound card, the product string for USB headsets.
+    pub card_name: Option<String>,
+    pub muted: bool,
+}
+
+/// The sound server, a trait so the sync can be tested without one.
+pub trait AudioBackend {
+    fn sources(&mut self) -> Result<Vec<Source>, Box<dyn Error>>;
+    fn set_source_mute(&mut self, name: &str, muted: bool) -> Result<(), Box<dyn Error>>;
+}
+
+/// Talks to PulseAudio or PipeWire through `pactl`.
+pub struct Pactl;
+
+impl AudioBackend for Pactl {
+    fn sources(&mut self) -> Result<Vec<Source>, Box<dyn Error>> {
+        let output = process::Command::new("pactl")
+            .args(["--format=json", "list", "sources"])
+            .output()?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr)
+                .trim()
+                .to_string()
+                .into());
+        }
+        parse_sources(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn set_source_mute(&mut self, name: &str, muted: bool) -> Result<(), Box<dyn Error>> {
+        let status = process::Command::new("pactl")
+            .args(["set-source-mute", name, if muted { "1" } else { "0" }])
+            .status()?;
+        if !status.success() {
+            return Err(format!("pactl set-source-mute failed with {status}").into());
+        }
+        Ok(())
+    }
+}
+
+/// The sources in the output of `pactl --format=json list sources`, without the monitors of outputs.
+fn parse_sources(json: &str) -> Result<Vec<Source>, Box<dyn Error>> {
+    let sources: Vec<serde_json::Value> = serde_json::from_str(json)?;
+    let property = |source: &serde_json::Value, name: &str| {
+        source["properties"][name].as_str().map(str::to_string)
+    };
+    Ok(sources
+        .iter()
+        .filter(|source| property(source, "device.class").as_deref() != Some("monitor"))
+        .filter_map(|source| {
+            Some(Source {
+                name: source["name"].as_str()?.to_string(),
+                card_name: property(source, "alsa.card_name")
+                    .or_else(|| property(source, "device.product.name")),
+                muted: source["mute"].as_bool()?,
+            })
+        })
+        .collect())
+}
+
+/// Runs a `MuteSync` with `pactl` on its own thread, so the sound server never holds up the headset.
+/// The headset is muted through the requests of its device thread, the sync stops when this is dropped.
+pub struct MuteSyncThread {
+    headset: Sender<(String, Option<bool>)>,
+}
+
+impl MuteSyncThread {
+    pub fn spawn(requests: Sender<Request>) -> Self {
+        let (headset, changes) = mpsc::channel();
+        std::thread::spawn(move || MuteSync::new(Pactl).run(&changes, &requests));
+        MuteSyncThread { headset }
+    }
+
+    /// Passes the mute state of the headset on to the sync thread.
+    pub fn headset_changed(&self, device_name: &str, muted: Option<bool>) {
+        let _ = self.headset.send((device_name.to_string(), muted));
+    }
+}
+
+/// Mirrors the mute state of the headset onto the source of its sound card and back.
+/// Each side is only followed when it changed, so a change never bounces back and forth.
+pub struct MuteSync<B: AudioBackend> {
+    backend: B,
+    headset_muted: Option<bool>,
+    source_muted: Option<bool>,
+    last_error: Option<String>,
+    follow_error: Option<String>,
+}
+
+impl<B: AudioBackend> MuteSync<B> {
+    pub fn new(backend: B) -> Self {
+        MuteSync {
+            backend,
+            headset_muted: None,
+            source_muted: None,
+            last_error: None,
+            follow_error: None,
+        }
+    }
+
+    /// Follows the headset changes received on `changes` and polls the source in between,
+    /// until the sender is dropped.
+    fn run(mut self, changes: &Receiver<(String, Option<bool>)>, requests: &Sender<Request>) {
+        let mut device_name = None;
+        let mut next_poll = Instant::now() + SOURCE_POLL_INTERVAL;
+        loop {
+            match changes.recv_timeout(next_poll.saturating_duration_since(Instant::now())) {
+                Ok((name, muted)) => {
+                    self.headset_changed(&name, muted);
+                    device_name = Some(name);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    next_poll = Instant::now() + SOURCE_POLL_INTERVAL;
+                    let Some(command) = device_name.as_deref().and_then(|n| self.poll_source(n))
+                    else {
+                        continue;
+                    };
+                    if !self.follow_source(command, requests) {
+                        return;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Sends `command` to the device thread and waits for the headset to take it.
+    /// The source counts as followed once the changed headset is passed back, a failed command is
+    /// sent again on the next poll. Returns false if the device thread is gone.
+    fn follow_source(&mut self, command: Command, requests: &Sender<Request>) -> bool {
+        let (reply, result) = mpsc::channel();
+        let request = Request {
+            action: command.into(),
+            reply: Some(reply),
+            deadline: None,
+        };
+        if requests.send(request).is_err() {
+            return false;
+        }
+        match result.recv() {
+            Ok(Ok(_)) => self.follow_error = None,
+            Ok(Err(error)) => {
+                let error = error.to_string();
+                if self.follow_error.as_ref() != Some(&error) {
+                    eprintln!("Muting the headset like the microphone failed: {error}");
+                }
+                self.follow_error = Some(error);
+            }
+            Err(_) => return false,
+        }
+        true
+    }
+
+    /// Mutes the source of `device_name` like the headset, if the headset changed.
+    pub fn headset_changed(&mut self, device_name: &str, muted: Option<bool>) {
+        let Some(muted) = muted.filter(|muted| Some(*muted) != self.headset_muted) else {
+            return;
+        };
+        self.headset_muted = Some(muted);
+        let result = self
+            .find_source(device_name)
+            .and_then(|source| match source {
+                Some(source) if source.muted != muted => {
+                    self.backend.set_source_mute(&source.name, muted)
+                }
+                _ => Ok(()),
+            });
+        self.source_muted = Some(muted);
+        self.report(result);
+    }
+
+    /// The command muting the headset like its source, if the source changed since the headset
+    /// was last passed to `headset_changed`.
+    pub fn poll_source(&mut self, device_name: &str) -> Option<Command> {
+        let source = match self.find_source(device_name) {
+            Ok(source) => source?,
+            Err(e) => {
+                self.report(Err(e));
+                return None;
+            }
+        };
+        self.report(Ok(()));
+        if Some(source.muted) == self.source_muted {
+            return None;
+        }
+        // Until the headset was read, it's the one setting the state.
+        match self.headset_muted {
+            Some(muted) if muted != source.muted => Some(Command::SetMute(source.muted)),
+            _ => None,
+        }
+    }
+
+    fn find_source(&mut self, device_name: &str) -> Result<Option<Source>, Box<dyn Error>> {
+        let device_name = device_name.to_lowercase();
+        Ok(self.backend.sources()?.into_iter().find(|source| {
+            source
+                .card_name
+                .as_ref()
+                .is_some_and(|card_name| card_name.to_lowercase().contains(&device_name))
+        }))
+    }
+
+    /// Prints an error once, not every time the source is polled.
+    fn report(&mut self, result: Result<(), Box<dyn Error>>) {
+        let error = result.err().map(|e| e.to_string());
+        if let Some(error) = error
+            .as_ref()
+            .filter(|error| self.last_error.as_ref() != Some(*error))
+        {
+            eprintln!("Syncing the microphone mute failed with error: {error}");
+        }
+        self.last_error = error;
+    }
+}
+
+#[test]
+fn test_mute_sync() {
+    #[derive(Default)]
+    struct FakeBackend {
+        sources: Vec<Source>,
+        changes: Vec<(String, bool)>,
+    }
+
+    impl AudioBackend for &mut FakeBackend {
+        fn sources(&mut self) -> Result<Vec<Source>, Box<dyn Error>> {
+            Ok(self.sources.clone())
+        }
+
+        fn set_source_mute(&mut self, name: &str, muted: bool) -> Result<(), Box<dyn Error>> {
+            self.changes.push((name.to_string(), muted));
+            for source in self.sources.iter_mut().filter(|source| source.name == name) {
+                source.muted = muted;
+            }
+            Ok(())
+        }
+    }
+
+    let name = "HyperX Cloud II Wireless";
+    let mut backend = FakeBackend {
+        sources: vec![
+            Source {
+                name: "builtin".to_string(),
+                card_name: Some("HDA Intel PCH".to_string()),
+                muted: false,
+            },
+            Source {
+                name: "hyperx".to_string(),
+                card_name: Some("HyperX Cloud II Wireless".to_string()),
+                muted: false,
+            },
+        ],
+        ..Default::default()
+    };
+    let mut sync = MuteSync::new(&mut backend);
+
+    // Nothing is decided before the headset was read.
+    assert_eq!(sync.poll_source(name), None);
+    sync.headset_changed(name, Some(true));
+    sync.headset_changed(name, Some(true));
+    assert_eq!(sync.poll_source(name), None);
+    assert_eq!(sync.backend.changes, [("hyperx".to_string(), true)]);
+
+    sync.backend.sources[1].muted = false;
+    assert_eq!(sync.poll_source(name), Some(Command::SetMute(false)));
+    // Asked again until the headset confirms the change, which doesn't go back to the source.
+    assert_eq!(sync.poll_source(name), Some(Command::SetMute(false)));
+    sync.headset_changed(name, Some(false));
+    assert_eq!(sync.poll_source(name), None);
+    assert_eq!(sync.backend.changes.len(), 1);
+    assert!(!sync.backend.sources[0].muted);
+}
+
+#[test]
+fn test_parse_pactl_sources() {
+    let sources = parse_sources(
+        r#"[
+        {"index": 55, "name": "alsa_output.usb-HP__Inc_HyperX_Cloud_II_Wireless-00.analog-stereo.monitor",
+         "mute": false, "properties": {"device.class": "monitor", "alsa.card_name": "HyperX Cloud II Wireless"}},
+        {"index": 56, "name": "alsa_input.usb-HP__Inc_HyperX_Cloud_II_Wireless-00.mono-fallback",
+         "mute": true, "properties": {"device.class": "sound", "alsa.card_name": "HyperX Cloud II Wireless"}}
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        sources,
+        [Source {
+            name: "alsa_input.usb-HP__Inc_HyperX_Cloud_II_Wireless-00.mono-fallback".to_string(),
+            card_name: Some("HyperX Cloud II Wireless".to_string()),
+            muted: true,
+        }]
+    );
+}
@@ -0,0 +1,195 @@
+use dbus::arg::{PropMap, Variant};
+use dbus::blocking::Connection;
+use hyper_headset::devices::{ChargingStatus, DeviceState};
+use std::{collections::BTreeSet, error::Error, time::Duration};
+
+/// A low battery notification is only shown again once the level rose this far above the threshold.
+const HYSTERESIS: u8 = 5;
+/// How long the mute state is shown after toggling it, in milliseconds.
+const OSD_TIMEOUT: i32 = 1500;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+    pub icon: &'static str,
+}
+
+/// Decides which changes of a device are worth a desktop notification.
+/// Every notification is shown once per transition, not on every refresh.
+pub struct Notifier {
+    /// Battery levels in percent that trigger a notification when the level drops to them.
+    thresholds: Vec<u8>,
+    /// Thresholds already notified about, rearmed once the battery recovered.
+    fired: BTreeSet<u8>,
+    /// Changes are still tracked while disabled, so enabling doesn't notify about old ones.
+    enabled: bool,
+    charging: Option<ChargingStatus>,
+    connected: Option<bool>,
+}
+
+impl Notifier {
+    pub fn new(thresholds: &[u8]) -> Self {
+        Notifier {
+            thresholds: thresholds.to_vec(),
+            fired: BTreeSet::new(),
+            enabled: true,
+            charging: None,
+            connected: None,
+        }
+    }
+
+    pub fn set_thresholds(&mut self, thresholds: &[u8]) {
+        self.thresholds = thresholds.to_vec();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// The notifications caused by the changes since the last call.
+    pub fn check(&mut self, state: &DeviceState) -> Vec<Notification> {
+        let name = state.device_name.as_deref().unwrap_or("Headset");
+        let mut notifications = Vec::new();
+
+        if let (Some(true), Some(false)) = (self.connected, state.connected) {
+            notifications.push(Notification {
+                summary: format!("{name} disconnected"),
+                body: "The headset was turned off or is out of range.".to_string(),
+                icon: "audio-headset",
+            });
+        }
+        self.connected = state.connected.or(self.connected);
+
+        // The charging status is unknown until the first refresh, that is not a transition.
+        if let (Some(previous), Some(charging)) = (self.charging, state.charging) {
+            if previous != charging {
+                let message = match charging {
+                    ChargingStatus::FullyCharged => {
+                        Some(("The battery is fully charged.", "battery-full-charged"))
+                    }
+                    ChargingStatus::ChargeError => {
+                        Some(("Charging the battery failed.", "battery-missing"))
+                    }
+                    _ => None,
+                };
+                notifications.extend(message.map(|(body, icon)| Notification {
+                    summary: name.to_string(),
+                    body: body.to_string(),
+                    icon,
+                }));
+            }
+        }
+        self.charging = state.charging.or(self.charging);
+
+        if let Some(level) = state.battery_level {
+            self.fired
+                .retain(|threshold| level <= threshold.saturating_add(HYSTERESIS));
+            let charging = matches!(
+                state.charging,
+                Some(ChargingStatus::Charging | ChargingStatus::FullyCharged)
+            );
+            let crossed: Vec<u8> = self
+                .thresholds
+                .iter()
+                .copied()
+                .filter(|threshold| level <= *threshold && !self.fired.contains(threshold))
+                .collect();
+            // Only the lowest crossed threshold is worth mentioning, the others are skipped.
+            if let Some(lowest) = crossed.iter().min().filter(|_| !charging) {
+                notifications.push(Notification {
+                    summary: format!("{name} battery low"),
+                    body: format!("{level}% left (below {lowest}%)"),
+                    icon: "battery-caution",
+                });
+                self.fired.extend(crossed);
+            }
+        }
+        if !self.enabled {
+            notifications.clear();
+        }
+        notifications
+    }
+}
+
+/// Shows `notification` through the freedesktop notification service on the session bus.
+pub fn show(notification: &Notification) -> Result<(), Box<dyn Error>> {
+    notify(notification, PropMap::new(), -1)
+}
+
+/// Briefly shows the mute state after it was toggled, like the on-screen display of a volume key.
+/// It isn't kept in the notification history and replaces the previous one where supported.
+pub fn show_mute(name: &str, muted: bool) -> Result<(), Box<dyn Error>> {
+    let (body, icon) = if muted {
+        ("Microphone muted", "microphone-sensitivity-muted")
+    } else {
+        ("Microphone on", "microphone-sensitivity-high")
+    };
+    let notification = Notification {
+        summary: name.to_string(),
+        body: body.to_string(),
+        icon,
+    };
+    let mut hints = PropMap::new();
+    hints.insert("transient".to_string(), Variant(Box::new(true)));
+    hints.insert(
+        "x-canonical-private-synchronous".to_string(),
+        Variant(Box::new(format!("{}-mute", env!("CARGO_PKG_NAME")))),
+    );
+    notify(&notification, hints, OSD_TIMEOUT)
+}
+
+fn notify(notification: &Notification, hints: PropMap, timeout: i32) -> Result<(), Box<dyn Error>> {
+    let connection = Connection::new_session()?;
+    let proxy = connection.with_proxy(
+        "org.freedesktop.Notifications",
+        "/org/freedesktop/Notifications",
+        Duration::from_secs(5),
+    );
+    let _: (u32,) = proxy.method_call(
+        "org.freedesktop.Notifications",
+        "Notify",
+        (
+            env!("CARGO_PKG_NAME"),
+            0u32,
+            notification.icon,
+            notification.summary.as_str(),
+            notification.body.as_str(),
+            Vec::<String>::new(),
+            hints,
+            timeout,
+        ),
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_notifications_are_not_repeated() {
+    let state = &mut DeviceState::without_transport(0x018B, 0x03F0);
+    state.connected = Some(true);
+    state.charging = Some(ChargingStatus::NotCharging);
+    let mut notifier = Notifier::new(&[20, 10, 5]);
+    let battery = |notifier: &mut Notifier, state: &mut DeviceState, level| {
+        state.battery_level = Some(level);
+        notifier.check(state).len()
+    };
+
+    assert_eq!(battery(&mut notifier, state, 50), 0);
+    assert_eq!(battery(&mut notifier, state, 20), 1);
+    assert_eq!(battery(&mut notifier, state, 19), 0);
+    // Fluctuating around the threshold doesn't notify again.
+    assert_eq!(battery(&mut notifier, state, 22), 0);
+    assert_eq!(battery(&mut notifier, state, 20), 0);
+    // Dropping below two thresholds at once notifies once.
+    assert_eq!(battery(&mut notifier, state, 4), 1);
+    assert_eq!(battery(&mut notifier, state, 4), 0);
+
+    state.charging = Some(ChargingStatus::FullyCharged);
+    assert_eq!(battery(&mut notifier, state, 100), 1);
+    state.charging = Some(ChargingStatus::NotCharging);
+    assert_eq!(battery(&mut notifier, state, 20), 1);
+
+    state.connected = Some(false);
+    assert_eq!(notifier.check(state).len(), 1);
+    assert_eq!(notifier.check(state).len(), 0);
+}
@@ -1,10 +1,22 @@
-use hyper_headset::devices::DeviceState;
-use ksni::{menu::StandardItem, Handle, MenuItem, ToolTip, Tray, TrayService};
+use crate::tray_icon::{self, IconStatus};
+use hyper_headset::config::{load_config, Config, DEFAULT_PROFILE};
+use hyper_headset::devices::{Capabilities, ChargingStatus, Command, DeviceState};
+use hyper_headset::monitor::Request;
+use ksni::{
+    menu::{CheckmarkItem, RadioGroup, RadioItem, StandardItem, SubMenu},
+    Handle, Icon, MenuItem, ToolTip, Tray, TrayService,
+};
+use std::{sync::mpsc::Sender, time::Duration};
 
 pub struct TrayHandler {
     handle: Handle<StatusTray>,
 }
 
+// Offered in the automatic shutdown submenu, 0 disables it.
+const AUTOMATIC_SHUTDOWN_MINUTES: [u8; 7] = [0, 5, 10, 15, 20, 30, 60];
+// At most this many side tone volume levels are offered.
+const SIDE_TONE_VOLUME_LEVELS: u16 = 11;
+
 const NO_COMPATIBLE_DEVICE: &str = "No compatible device found.\nIs the dongle plugged in?\nIf you are using Linux did you add the Udev rules?";
 
 impl TrayHandler {
@@ -24,16 +36,79 @@ impl TrayHandler {
             ),
             Some(true) => (device_state.to_string_no_padding(), device_state.device_name.clone()),
         };
+        let settings = Settings::from(device_state);
         self.handle.update(|tray| {
             tray.message = message;
             tray.device_name = name;
+            tray.settings = settings;
+        })
+    }
+
+    /// Lets the menu select one of the profiles in `config`.
+    pub fn set_profiles(&self, config: &Config) {
+        let profiles = config.profiles.keys().cloned().collect();
+        let profile = config.profile.clone();
+        self.handle.update(|tray| {
+            tray.profiles = profiles;
+            tray.profile = profile;
+        })
+    }
+
+    /// Lets the menu change the settings the device supports, changes are sent to `requests`.
+    pub fn set_controls(&self, capabilities: Capabilities, requests: Sender<Request>) {
+        self.handle.update(|tray| {
+            tray.controls = Some(Controls {
+                capabilities,
+                requests,
+            });
         })
     }
 }
 
+impl Drop for TrayHandler {
+    fn drop(&mut self) {
+        self.handle.shutdown();
+    }
+}
+
 pub struct StatusTray {
     device_name: Option<String>,
     message: String,
+    settings: Settings,
+    controls: Option<Controls>,
+    profiles: Vec<String>,
+    profile: Option<String>,
+}
+
+/// The confirmed values shown in the menu and the icon.
+#[derive(Debug, Default)]
+struct Settings {
+    connected: bool,
+    battery_level: Option<u8>,
+    charging: Option<ChargingStatus>,
+    muted: Option<bool>,
+    side_tone_on: Option<bool>,
+    side_tone_volume: Option<u8>,
+    automatic_shutdown_after: Option<Duration>,
+}
+
+impl From<&DeviceState> for Settings {
+    fn from(state: &DeviceState) -> Self {
+        Settings {
+            connected: state.connected == Some(true),
+            battery_level: state.battery_level,
+            charging: state.charging,
+            muted: state.muted,
+            side_tone_on: state.side_tone_on,
+            side_tone_volume: state.side_tone_volume,
+            automatic_shutdown_after: state.automatic_shutdown_after,
+        }
+    }
+}
+
+struct Controls {
+    capabilities: Capabilities,
+    requests: Sender<Request>,
 }
 
 impl StatusTray {
@@ -41,16 +116,184 @@ impl StatusTray {
         StatusTray {
             device_name: None,
             message: NO_COMPATIBLE_DEVICE.to_string(),
+            settings: Settings::default(),
+            controls: None,
+            profiles: Vec::new(),
+            profile: None,
+        }
+    }
+
+    /// The menu shows the new value once the device confirmed it.
+    fn send(&self, command: Command) {
+        if let Some(controls) = &self.controls {
+            let _ = controls.requests.send(Request {
+                action: command.into(),
+                reply: None,
+                deadline: None,
+            });
+        }
+    }
+
+    fn control_items(&self) -> Vec<MenuItem<Self>> {
+        let Some(controls) = self.controls.as_ref().filter(|_| self.settings.connected) else {
+            return Vec::new();
+        };
+        let capabilities = &controls.capabilities;
+        let settings = &self.settings;
+        let mut items = vec![MenuItem::Separator];
+        if capabilities.mute.writable {
+            let muted = settings.muted == Some(true);
+            items.push(
+                CheckmarkItem {
+                    label: "Muted".into(),
+                    checked: muted,
+                    activate: Box::new(move |tray: &mut Self| tray.send(Command::SetMute(!muted))),
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+        if capabilities.side_tone.writable {
+            let on = settings.side_tone_on == Some(true);
+            items.push(
+                CheckmarkItem {
+                    label: "Side tone".into(),
+                    checked: on,
+                    activate: Box::new(move |tray: &mut Self| tray.send(Command::SetSideTone(!on))),
+                    ..Default::default()
+                }
+                .into(),
+            );
+        }
+        if capabilities.side_tone_volume.writable {
+            let levels = side_tone_volume_levels(capabilities);
+            items.push(radio_submenu(
+                "Side tone volume",
+                levels.iter().map(u8::to_string).collect(),
+                settings
+                    .side_tone_volume
+                    .and_then(|volume| levels.iter().position(|level| *level == volume)),
+                move |tray, index| tray.send(Command::SetSideToneVolume(levels[index])),
+            ));
+        }
+        if capabilities.automatic_shutdown.writable {
+            let selected = settings.automatic_shutdown_after.and_then(|after| {
+                AUTOMATIC_SHUTDOWN_MINUTES
+                    .iter()
+                    .position(|minutes| *minutes as u64 * 60 == after.as_secs())
+            });
+            let labels = AUTOMATIC_SHUTDOWN_MINUTES
+                .iter()
+                .map(|minutes| match minutes {
+                    0 => "Never".to_string(),
+                    minutes => format!("{minutes} min"),
+                })
+                .collect();
+            items.push(radio_submenu(
+                "Automatic shutdown",
+                labels,
+                selected,
+                |tray, index| {
+                    let minutes = AUTOMATIC_SHUTDOWN_MINUTES[index] as u64;
+                    tray.send(Command::SetAutomaticShutdown(Duration::from_secs(
+                        minutes * 60,
+                    )))
+                },
+            ));
         }
+        if items.len() == 1 {
+            items.clear();
+        }
+        items
+    }
+
+    /// Selecting a profile stores it, the device thread applies it once it notices the change.
+    fn profile_item(&self) -> Option<MenuItem<Self>> {
+        if self.profiles.is_empty() || self.controls.is_none() {
+            return None;
+        }
+        let names: Vec<String> = std::iter::once(DEFAULT_PROFILE.to_string())
+            .chain(self.profiles.iter().cloned())
+            .collect();
+        let selected = self
+            .profile
+            .as_ref()
+            .and_then(|profile| names.iter().position(|name| name == profile))
+            .unwrap_or(0);
+        let select = {
+            let names = names.clone();
+            move |_: &mut Self, index: usize| {
+                if let Err(e) = load_config().select_profile(&names[index]) {
+                    eprintln!("Selecting the profile failed with error: {e}");
+                }
+            }
+        };
+        Some(radio_submenu("Profile", names, Some(selected), select))
     }
 }
 
+/// Evenly spread over the range the device accepts.
+fn side_tone_volume_levels(capabilities: &Capabilities) -> Vec<u8> {
+    let (min, max) = (
+        *capabilities.side_tone_volume_range.start() as u16,
+        *capabilities.side_tone_volume_range.end() as u16,
+    );
+    let span = max.saturating_sub(min);
+    let steps = span.clamp(1, SIDE_TONE_VOLUME_LEVELS - 1);
+    let mut levels: Vec<u8> = (0..=steps)
+        .map(|step| (min + span * step / steps) as u8)
+        .collect();
+    levels.dedup();
+    levels
+}
+
+fn radio_submenu(
+    label: &str,
+    options: Vec<String>,
+    selected: Option<usize>,
+    select: impl Fn(&mut StatusTray, usize) + 'static,
+) -> MenuItem<StatusTray> {
+    SubMenu {
+        label: label.into(),
+        submenu: vec![RadioGroup {
+            // Nothing is selected while the value is unknown.
+            selected: selected.unwrap_or(usize::MAX),
+            select: Box::new(select),
+            options: options
+                .into_iter()
+                .map(|label| RadioItem {
+                    label,
+                    ..Default::default()
+                })
+                .collect(),
+        }
+        .into()],
+        ..Default::default()
+    }
+    .into()
+}
+
 impl Tray for StatusTray {
     fn id(&self) -> String {
         env!("CARGO_PKG_NAME").into()
     }
     fn icon_name(&self) -> String {
-        "audio-headset".into()
+        // Trays prefer a themed icon over the pixmap, so only the placeholder uses one.
+        match self.controls {
+            Some(_) => String::new(),
+            None => "audio-headset".into(),
+        }
+    }
+    fn icon_pixmap(&self) -> Vec<Icon> {
+        if self.controls.is_none() {
+            return Vec::new();
+        }
+        let settings = &self.settings;
+        vec![tray_icon::render(IconStatus {
+            battery_level: settings.battery_level.filter(|_| settings.connected),
+            charging: settings.charging == Some(ChargingStatus::Charging),
+            muted: settings.connected && settings.muted == Some(true),
+        })]
     }
     fn tool_tip(&self) -> ToolTip {
         let description = self.message.clone().lines().filter(|l| !l.contains("Unknown")).collect::<Vec<&str>>().join("\n");
@@ -74,6 +317,9 @@ impl Tray for StatusTray {
                 .into()
             })
             .collect();
+        state_items.extend(self.control_items());
+        state_items.extend(self.profile_item());
+        state_items.push(MenuItem::Separator);
         let exit = StandardItem {
             label: "Exit".into(),
             icon_name: "application-exit".into(),
@@ -84,3 +330,25 @@ impl Tray for StatusTray {
         state_items
     }
 }
+
+#[test]
+fn test_side_tone_volume_levels() {
+    use hyper_headset::devices::{
+        descriptor::built_in_descriptors, generic::GenericDevice, Device,
+    };
+
+    let state = DeviceState::without_transport(0x018B, 0x03F0);
+    let mut capabilities = GenericDevice::new(built_in_descriptors()[0].clone(), state)
+        .unwrap()
+        .capabilities();
+    assert_eq!(capabilities.side_tone_volume_range, 0..=255);
+    capabilities.side_tone_volume_range = 20..=120;
+    assert_eq!(
+        side_tone_volume_levels(&capabilities),
+        [20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120]
+    );
+    capabilities.side_tone_volume_range = 2..=5;
+    assert_eq!(side_tone_volume_levels(&capabilities), [2, 3, 4, 5]);
+    capabilities.side_tone_volume_range = 3..=3;
+    assert_eq!(side_tone_volume_levels(&capabilities), [3]);
+}
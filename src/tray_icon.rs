@@ -0,0 +1,150 @@
+// Draws the tray icon: a battery gauge with a bolt while charging and a red badge while muted.
+use ksni::Icon;
+
+const SIZE: usize = 32;
+
+const TRANSPARENT: u32 = 0x0000_0000;
+const OUTLINE: u32 = 0xFFBD_BDBD;
+const DISCONNECTED: u32 = 0xFF75_7575;
+const FULL: u32 = 0xFF4C_AF50;
+const LOW: u32 = 0xFFFF_C107;
+const CRITICAL: u32 = 0xFFF4_4336;
+const WHITE: u32 = 0xFFFF_FFFF;
+
+// The battery body including its 2 pixel outline, the terminal sits on the right.
+const BODY: (usize, usize, usize, usize) = (2, 8, 25, 23);
+const TERMINAL: (usize, usize, usize, usize) = (26, 12, 28, 19);
+
+#[rustfmt::skip]
+const BOLT: [&str; 11] = [
+    ".....##.",
+    "....##..",
+    "...##...",
+    "..##....",
+    ".#######",
+    "#######.",
+    "....##..",
+    "...##...",
+    "..##....",
+    ".##.....",
+    "##......",
+];
+
+/// What the icon shows, `battery_level` is `None` while the headset is not connected.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IconStatus {
+    pub battery_level: Option<u8>,
+    pub charging: bool,
+    pub muted: bool,
+}
+
+struct Canvas {
+    pixels: Vec<u32>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Canvas {
+            pixels: vec![TRANSPARENT; SIZE * SIZE],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: u32) {
+        if x < SIZE && y < SIZE {
+            self.pixels[y * SIZE + x] = color;
+        }
+    }
+
+    /// Fills the rectangle between the two corners, both inclusive.
+    fn fill(&mut self, (left, top, right, bottom): (usize, usize, usize, usize), color: u32) {
+        for y in top..=bottom {
+            for x in left..=right {
+                self.set(x, y, color);
+            }
+        }
+    }
+
+    fn into_icon(self) -> Icon {
+        Icon {
+            width: SIZE as i32,
+            height: SIZE as i32,
+            data: self.pixels.into_iter().flat_map(u32::to_be_bytes).collect(),
+        }
+    }
+}
+
+pub fn render(status: IconStatus) -> Icon {
+    let mut canvas = Canvas::new();
+    let (left, top, right, bottom) = BODY;
+    let outline = match status.battery_level {
+        Some(_) => OUTLINE,
+        None => DISCONNECTED,
+    };
+    canvas.fill(BODY, outline);
+    canvas.fill(TERMINAL, outline);
+    canvas.fill((left + 2, top + 2, right - 2, bottom - 2), TRANSPARENT);
+
+    if let Some(level) = status.battery_level {
+        let color = match level {
+            0..=10 => CRITICAL,
+            11..=30 => LOW,
+            _ => FULL,
+        };
+        let width = (right - 2) - (left + 2) + 1;
+        // Even an empty battery shows a sliver, so it doesn't look disconnected.
+        let filled = (width * level.min(100) as usize / 100).max(1);
+        canvas.fill((left + 2, top + 2, left + 1 + filled, bottom - 2), color);
+    }
+
+    if status.charging {
+        let (x0, y0) = ((left + right) / 2 - 3, top + 2);
+        for (y, row) in BOLT.iter().enumerate() {
+            for (x, pixel) in row.chars().enumerate() {
+                if pixel == '#' {
+                    canvas.set(x0 + x, y0 + y, WHITE);
+                }
+            }
+        }
+    }
+
+    if status.muted {
+        let (cx, cy, radius) = (25i32, 25i32, 6i32);
+        for y in cy - radius..=cy + radius {
+            for x in cx - radius..=cx + radius {
+                if (x - cx).pow(2) + (y - cy).pow(2) <= radius.pow(2) {
+                    canvas.set(x as usize, y as usize, CRITICAL);
+                }
+            }
+        }
+        canvas.fill((21, 24, 29, 26), WHITE);
+    }
+    canvas.into_icon()
+}
+
+#[test]
+fn test_render_icon() {
+    let pixel = |icon: &Icon, x: usize, y: usize| {
+        let i = (y * SIZE + x) * 4;
+        u32::from_be_bytes(icon.data[i..i + 4].try_into().unwrap())
+    };
+    let icon = render(IconStatus {
+        battery_level: Some(50),
+        ..Default::default()
+    });
+    assert_eq!(icon.data.len(), SIZE * SIZE * 4);
+    assert_eq!(pixel(&icon, 5, 15), FULL);
+    assert_eq!(pixel(&icon, 22, 15), TRANSPARENT);
+
+    let icon = render(IconStatus {
+        battery_level: Some(5),
+        charging: false,
+        muted: true,
+    });
+    assert_eq!(pixel(&icon, 4, 15), CRITICAL);
+    assert_eq!(pixel(&icon, 25, 25), WHITE);
+    assert_eq!(pixel(&icon, 25, 20), CRITICAL);
+
+    let icon = render(IconStatus::default());
+    assert_eq!(pixel(&icon, 2, 8), DISCONNECTED);
+    assert_eq!(pixel(&icon, 5, 15), TRANSPARENT);
+}